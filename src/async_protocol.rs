@@ -0,0 +1,704 @@
+#![warn(clippy::all)]
+
+use crate::cancel::CancelToken;
+use crate::command::ServerCommand;
+use crate::endpoint::{Endpoint, Endpoints};
+use crate::error::Err;
+use crate::fields::{FieldMap, Report};
+use crate::identity::Identity;
+use crate::observer::SessionObserver;
+use crate::protocol::{parse_command, State, StateData, StateEnd, StateHelo, StatePow, DEFAULT_READ_TIMEOUT};
+use crate::solver::{ParallelSolver, Solver};
+use crate::ssl::{create_ssl, create_ssl_context, log_negotiated, verification_error};
+use crate::tls::{TlsConfig, POLL_INTERVAL};
+use crate::transcript::{Event, Transcript, REDACTED};
+use crate::userdata::UserData;
+use crate::Res;
+use log::{debug, info, warn};
+use std::error::Error;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio_openssl::SslStream;
+
+/// An error passed back from the blocking thread pool.
+type SendErr = Box<dyn Error + Send + Sync>;
+
+/// Make an error of a blocking task sendable back to the runtime. Errors of the crate and I/O
+/// errors keep their type, so callers can still tell them apart; any other error is kept as
+/// its message.
+fn sendable(e: Box<dyn Error>) -> SendErr {
+  let e = match e.downcast::<Err>() {
+    Ok(e) => return e,
+    Err(e) => e,
+  };
+
+  match e.downcast::<io::Error>() {
+    Ok(e) => e,
+    Err(e) => e.to_string().into(),
+  }
+}
+
+fn unsendable(e: SendErr) -> Box<dyn Error> {
+  e
+}
+
+/// Run a blocking function on the blocking thread pool.
+async fn run_blocking<R: Send + 'static>(f: impl FnOnce() -> Res<R> + Send + 'static) -> Res<R> {
+  tokio::task::spawn_blocking(move || f().map_err(sendable))
+    .await?
+    .map_err(unsendable)
+}
+
+/// Asynchronous variant of [`Exasol`](crate::protocol::Exasol) with the same typestate API.
+pub struct AsyncExasol<S: State = (), T: AsyncRead + AsyncWrite + Unpin = SslStream<TcpStream>> {
+  stream: T,
+  endpoint: Endpoint,
+  transcript: Option<Transcript>,
+  observers: Vec<Box<dyn SessionObserver>>,
+  fields: FieldMap,
+  solver: Arc<dyn Solver>,
+  cancel: CancelToken,
+  read_timeout: Duration,
+  close: bool,
+  buffer: Vec<u8>,
+  state: S,
+}
+
+impl<S1: State, T: AsyncRead + AsyncWrite + Unpin> AsyncExasol<S1, T> {
+  fn make<S0: State>(old: AsyncExasol<S0, T>) -> Res<Self> {
+    Self::make_with_state(old, Default::default())
+  }
+
+  fn make_with_state<S0: State>(old: AsyncExasol<S0, T>, new_state: S1) -> Res<Self> {
+    let mut new = Self {
+      stream: old.stream,
+      endpoint: old.endpoint,
+      transcript: old.transcript,
+      observers: old.observers,
+      fields: old.fields,
+      solver: old.solver,
+      cancel: old.cancel,
+      read_timeout: old.read_timeout,
+      close: old.close,
+      buffer: old.buffer,
+      state: new_state,
+    };
+
+    new.notify(|observer| observer.state_changed(S0::NAME, S1::NAME));
+    new.record(&Event::State { state: S1::NAME })?;
+
+    Ok(new)
+  }
+}
+
+impl<S: State, T: AsyncRead + AsyncWrite + Unpin> AsyncExasol<S, T> {
+  /// Get a reference to the endpoint the session is connected to.
+  pub fn endpoint(&self) -> &Endpoint {
+    &self.endpoint
+  }
+
+  /// Record the session to the given transcript.
+  pub fn with_transcript(mut self, mut transcript: Transcript) -> Res<Self> {
+    transcript.record(&Event::Connected { endpoint: &self.endpoint.to_string() })?;
+    self.transcript = Some(transcript);
+    Ok(self)
+  }
+
+  /// Report the events of the session to the given observer.
+  pub fn with_observer(mut self, observer: impl SessionObserver + 'static) -> Self {
+    self.observers.push(Box::new(observer));
+    self
+  }
+
+  /// Answer the data commands of the server using the given field mapping instead of the
+  /// built-in one.
+  pub fn with_fields(mut self, fields: FieldMap) -> Self {
+    self.fields = fields;
+    self
+  }
+
+  /// Solve the proof-of-work with the given solver instead of the built-in one.
+  pub fn with_solver(mut self, solver: impl Solver + 'static) -> Self {
+    self.solver = Arc::new(solver);
+    self
+  }
+
+  /// Stop the session when the given token is cancelled: the proof-of-work search is
+  /// stopped, the next read from the server fails and the TLS session is closed.
+  pub fn with_cancel(mut self, cancel: CancelToken) -> Self {
+    self.cancel = cancel;
+    self
+  }
+
+  /// Fail the session when the server sends nothing for the given time while the client
+  /// waits for a line, or when the TLS handshake takes longer, instead of after the default
+  /// of one minute.
+  pub fn with_read_timeout(mut self, read_timeout: Duration) -> Self {
+    self.read_timeout = read_timeout;
+    self
+  }
+
+  fn notify(&mut self, mut event: impl FnMut(&mut dyn SessionObserver)) {
+    for observer in &mut self.observers {
+      event(observer.as_mut());
+    }
+  }
+
+  /// Report an error of the session to the observers before returning it.
+  fn observe<R>(&mut self, res: Res<R>) -> Res<R> {
+    if let Err(e) = &res {
+      self.notify(|observer| observer.error(e.as_ref()));
+    }
+
+    res
+  }
+
+  /// Close the TLS session after the session was cancelled.
+  async fn close(&mut self) {
+    if std::mem::take(&mut self.close) {
+      if let Err(e) = self.stream.shutdown().await {
+        warn!("Could not close the session cleanly: {}", e);
+      }
+    }
+  }
+
+  fn record(&mut self, event: &Event<'_>) -> Res<()> {
+    if let Some(transcript) = &mut self.transcript {
+      transcript.record(event)?;
+    }
+
+    Ok(())
+  }
+
+  /// Read the next line from the server into the buffer. Fails when the session is cancelled
+  /// in the meantime, or when nothing was received for longer than the read timeout.
+  async fn read_line(&mut self) -> Res<()> {
+    let mut chunk = [0_u8; 1024];
+    let mut last_received = Instant::now();
+
+    self.buffer.clear();
+
+    loop {
+      let bytes = match tokio::time::timeout(POLL_INTERVAL, self.stream.read(&mut chunk)).await {
+        Ok(bytes) => bytes?,
+        Err(_) if self.cancel.is_cancelled() => {
+          self.close().await;
+          return Err::cancelled();
+        }
+        Err(_) if last_received.elapsed() >= self.read_timeout => {
+          return Err::timeout("data from the server", self.read_timeout);
+        }
+        Err(_) => continue,
+      };
+
+      if bytes == 0 {
+        return Err(
+          io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed before the end of the line").into(),
+        );
+      }
+
+      last_received = Instant::now();
+
+      if let Some(newline) = chunk[..bytes].iter().position(|&b| b == b'\n') {
+        self.buffer.extend_from_slice(&chunk[..newline]);
+        break;
+      }
+
+      self.buffer.extend_from_slice(&chunk[..bytes]);
+    }
+
+    let line = std::str::from_utf8(&self.buffer)?.trim_end_matches('\r');
+
+    for observer in &mut self.observers {
+      observer.line_received(line);
+    }
+
+    if let Some(transcript) = &mut self.transcript {
+      transcript.record(&Event::Received { line })?;
+    }
+
+    Ok(())
+  }
+
+  async fn send_line(&mut self, line: &str) -> Res<()> {
+    self.write_line(line).await?;
+    self.sent(line)
+  }
+
+  async fn write_line(&mut self, line: &str) -> Res<()> {
+    self.stream.write_all(line.as_bytes()).await?;
+    self.stream.write_all(b"\n").await?;
+    self.stream.flush().await?;
+    Ok(())
+  }
+
+  fn sent(&mut self, line: &str) -> Res<()> {
+    self.notify(|observer| observer.line_sent(line));
+    self.record(&Event::Sent { line })
+  }
+
+  /// Send the OK acknowledging the server's END. Observers learn about it as soon as it is
+  /// flushed, so a failure recording or reporting it cannot hide that the server has it.
+  async fn acknowledge_end(&mut self) -> Res<()> {
+    self.write_line("OK").await?;
+    self.notify(|observer| observer.end_acknowledged());
+    self.sent("OK")
+  }
+
+  /// Acknowledge an END the server sent before the data phase and fail with the state it
+  /// arrived in.
+  async fn ended<R>(&mut self) -> Res<R> {
+    self.acknowledge_end().await?;
+    Err::server_ended(S::NAME)
+  }
+}
+
+async fn connect_tcp(endpoints: &Endpoints) -> Res<(TcpStream, Endpoint)> {
+  if endpoints.proxy().is_some() {
+    // The tunnel is negotiated synchronously, each step blocking for up to the connect
+    // timeout, so it runs on the blocking thread pool rather than stalling the runtime.
+    let endpoints = endpoints.clone();
+    let (stream, endpoint) = run_blocking(move || endpoints.connect()).await?;
+    stream.set_nonblocking(true)?;
+    return Ok((TcpStream::from_std(stream)?, endpoint));
+  }
+
+  for endpoint in endpoints.ordered() {
+    debug!("Connecting to {} (timeout {:?})", endpoint, endpoints.connect_timeout());
+
+    let host = endpoint.host();
+    let connect = TcpStream::connect((host.as_str(), endpoint.port()));
+
+    match tokio::time::timeout(endpoints.connect_timeout(), connect).await {
+      Ok(Ok(stream)) => {
+        info!("Connected to {}", endpoint);
+        return Ok((stream, endpoint.clone()));
+      }
+      Ok(Err(e)) => warn!("Could not connect to {}: {}", endpoint, e),
+      Err(_) => warn!("Could not connect to {}: timed out", endpoint),
+    }
+  }
+
+  Err::no_reachable_endpoint(endpoints.endpoints().len())
+}
+
+impl AsyncExasol {
+  pub async fn new(tls: &TlsConfig, endpoints: &Endpoints) -> Res<Self> {
+    // Loading the identity reads files and may prompt for a passphrase.
+    let config = tls.clone();
+    let identity = run_blocking(move || Identity::load_checked(&config)).await?;
+    let (stream, endpoint) = connect_tcp(endpoints).await?;
+    debug!("Connected TCP stream: {:?}", stream);
+    let ctx = create_ssl_context(tls, &identity, &endpoint)?;
+    let ssl = create_ssl(&ctx, tls, &endpoint)?;
+    let stream = SslStream::new(ssl, stream)?;
+    Ok(Self {
+      stream,
+      endpoint,
+      transcript: None,
+      observers: vec![],
+      fields: FieldMap::builtin(),
+      solver: Arc::new(ParallelSolver),
+      cancel: CancelToken::new(),
+      read_timeout: DEFAULT_READ_TIMEOUT,
+      close: true,
+      buffer: vec![],
+      state: Default::default(),
+    })
+  }
+
+  pub async fn connect(mut self) -> Res<AsyncExasol<StateHelo>> {
+    let res = self.handshake_tls().await;
+    self.observe(res)?;
+    debug!("SSL stream connected");
+
+    let endpoint = self.endpoint.clone();
+    self.notify(|observer| observer.connected(&endpoint));
+
+    AsyncExasol::make(self)
+  }
+
+  /// Run the TLS handshake, failing when the session is cancelled in the meantime or the
+  /// handshake takes longer than the read timeout.
+  async fn handshake_tls(&mut self) -> Res<()> {
+    let start = Instant::now();
+
+    loop {
+      match tokio::time::timeout(POLL_INTERVAL, Pin::new(&mut self.stream).connect()).await {
+        Ok(Ok(())) => break,
+        Ok(Err(e)) => {
+          verification_error(self.stream.ssl())?;
+          return Err(e.into());
+        }
+        Err(_) if self.cancel.is_cancelled() => return Err::cancelled(),
+        Err(_) if start.elapsed() >= self.read_timeout => {
+          return Err::timeout("the TLS handshake", self.read_timeout);
+        }
+        Err(_) => {}
+      }
+    }
+
+    log_negotiated(self.stream.ssl());
+    Ok(())
+  }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncExasol<StateHelo, T> {
+  /// Run a session over an already established stream (e.g. an in-memory stream), starting
+  /// at the handshake.
+  pub fn from_stream(stream: T, endpoint: Endpoint) -> Self {
+    Self {
+      stream,
+      endpoint,
+      transcript: None,
+      observers: vec![],
+      fields: FieldMap::builtin(),
+      solver: Arc::new(ParallelSolver),
+      cancel: CancelToken::new(),
+      read_timeout: DEFAULT_READ_TIMEOUT,
+      close: false,
+      buffer: vec![],
+      state: Default::default(),
+    }
+  }
+
+  pub async fn handshake(mut self) -> Res<AsyncExasol<StatePow, T>> {
+    let res = self.helo().await;
+    self.observe(res)?;
+    AsyncExasol::make(self)
+  }
+
+  async fn helo(&mut self) -> Res<()> {
+    self.read_line().await?;
+
+    // Bound first, so that the error of `?` is not held across the awaits below and the
+    // future stays `Send`.
+    let command = parse_command(&self.buffer, StateHelo::NAME)?;
+
+    match command {
+      ServerCommand::Helo => self.send_line("EHLO").await,
+      ServerCommand::End => self.ended().await,
+      command => Err::unknown_or_unexpected_command(&command.name(), "HELO"),
+    }
+  }
+}
+
+/// Cancels the proof-of-work search when dropped, e.g. when the future awaiting it is
+/// dropped by the runtime.
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+  fn drop(&mut self) {
+    self.0.store(true, Ordering::Release);
+  }
+}
+
+/// The outcome of a proof-of-work search on the blocking thread pool, along with the
+/// observers it reported its progress to.
+type Search = (Result<Option<Vec<u8>>, SendErr>, Vec<Box<dyn SessionObserver>>);
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncExasol<StatePow, T> {
+  /// Solve the proof-of-work on the blocking thread pool. Dropping the returned future
+  /// cancels the search.
+  pub async fn pow(mut self) -> Res<AsyncExasol<StateData, T>> {
+    let res = self.solve().await;
+    let new_state = self.observe(res)?;
+    AsyncExasol::make_with_state(self, new_state)
+  }
+
+  async fn solve(&mut self) -> Res<StateData> {
+    self.read_line().await?;
+
+    let command = parse_command(&self.buffer, StatePow::NAME)?;
+
+    let (new_state, difficulty) = match command {
+      ServerCommand::Pow { authdata, difficulty } => (StateData::new(authdata), difficulty),
+      ServerCommand::End => return self.ended().await,
+      command => return Err::unknown_or_unexpected_command(&command.name(), "POW"),
+    };
+
+    let authdata = new_state.authdata().to_string();
+
+    self.notify(|observer| observer.pow_started(&authdata, difficulty));
+
+    // The search gets its own flag, so that dropping the future does not cancel other
+    // sessions sharing the cancel token.
+    let cancel = Arc::new(AtomicBool::new(false));
+    let _cancel_on_drop = CancelOnDrop(Arc::clone(&cancel));
+    let mut search = self.search(authdata, difficulty, Arc::clone(&cancel));
+
+    let (res, observers) = loop {
+      match tokio::time::timeout(POLL_INTERVAL, &mut search).await {
+        Ok(done) => break done?,
+        Err(_) if self.cancel.is_cancelled() => cancel.store(true, Ordering::Release),
+        Err(_) => {}
+      }
+    };
+    self.observers = observers;
+
+    let random_bytes = res.map_err(unsendable)?;
+
+    let random_bytes = match random_bytes {
+      Some(random_bytes) => random_bytes,
+      None if self.cancel.is_cancelled() => {
+        self.close().await;
+        return Err(Box::new(Err::PowCancelled));
+      }
+      None => return Err(Box::new(Err::CannotPow)),
+    };
+    let random_string = std::str::from_utf8(&random_bytes)?;
+
+    self.send_line(random_string).await?;
+
+    let digest = hex::encode(new_state.hash(random_string));
+    self.notify(|observer| observer.pow_solved(random_string, &digest));
+    self.record(&Event::Pow {
+      authdata: new_state.authdata(),
+      difficulty,
+      suffix: random_string,
+      digest: &digest,
+    })?;
+
+    Ok(new_state)
+  }
+
+  /// Start the proof-of-work search with the solver on the blocking thread pool. The
+  /// observers are moved along to report the progress of the search.
+  fn search(&mut self, authdata: String, difficulty: usize, cancel: Arc<AtomicBool>) -> JoinHandle<Search> {
+    let solver = Arc::clone(&self.solver);
+    let observers = parking_lot::Mutex::new(std::mem::take(&mut self.observers));
+
+    tokio::task::spawn_blocking(move || {
+      let progress = |iterations, elapsed| {
+        for observer in observers.lock().iter_mut() {
+          observer.pow_progress(iterations, elapsed);
+        }
+      };
+
+      let res = solver.solve(&authdata, difficulty, &cancel, &progress).map_err(sendable);
+      (res, observers.into_inner())
+    })
+  }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncExasol<StateEnd, T> {
+  /// Get a reference to the completeness report of the data phase.
+  pub fn report(&self) -> &Report {
+    self.state.report()
+  }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncExasol<StateData, T> {
+  pub async fn submit(mut self, userdata: &UserData) -> Res<AsyncExasol<StateEnd, T>> {
+    let res = self.submit_fields(userdata).await;
+    let report = self.observe(res)?;
+    AsyncExasol::make_with_state(self, StateEnd::new(report))
+  }
+
+  async fn submit_fields(&mut self, userdata: &UserData) -> Res<Report> {
+    loop {
+      self.read_line().await?;
+
+      let command = parse_command(&self.buffer, StateData::NAME)?;
+
+      let field = match command {
+        ServerCommand::End => {
+          self.acknowledge_end().await?;
+          let report = self.state.report(&self.fields, userdata);
+          self.notify(|observer| observer.report(&report));
+          self.record(&Event::Report { missing: report.missing(), duplicates: report.duplicates() })?;
+          self.notify(|observer| observer.end());
+          return Ok(report);
+        }
+        command => match self.state.field(&command, &self.fields, userdata)? {
+          Some(field) => field,
+          None => continue,
+        },
+      };
+
+      self.send_response(field.command(), &field.hash(), field.datum()).await?;
+      self.notify(|observer| observer.field_submitted(&field));
+    }
+  }
+
+  async fn send_response(&mut self, command: &str, hash: &str, datum: &str) -> Res<()> {
+    let line = format!("{} {}", hash, datum);
+    self.write_line(&line).await?;
+    self.notify(|observer| observer.line_sent(&line));
+
+    if let Some(transcript) = &mut self.transcript {
+      let datum = if transcript.redact() {
+        None
+      } else {
+        Some(datum)
+      };
+      let line = format!("{} {}", hash, datum.unwrap_or(REDACTED));
+      transcript.record(&Event::Sent { line: &line })?;
+      transcript.record(&Event::Response { command, hash, datum })?;
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::protocol::Field;
+  use std::future::Future;
+  use tokio::io::{duplex, AsyncBufReadExt, BufReader, DuplexStream};
+
+  /// Returns a fixed outcome for any proof-of-work.
+  struct FixedSolver(fn() -> Res<Option<Vec<u8>>>);
+
+  impl Solver for FixedSolver {
+    fn solve(
+      &self,
+      _authdata: &str,
+      _difficulty: usize,
+      _cancel: &AtomicBool,
+      _progress: &(dyn Fn(usize, Duration) + Sync),
+    ) -> Res<Option<Vec<u8>>> {
+      (self.0)()
+    }
+  }
+
+  /// Records the lines the client sent and the fields it submitted.
+  #[derive(Clone, Default)]
+  struct Recorder(Arc<parking_lot::Mutex<(Vec<String>, Vec<String>)>>);
+
+  impl SessionObserver for Recorder {
+    fn line_sent(&mut self, line: &str) {
+      self.0.lock().0.push(line.to_string());
+    }
+
+    fn field_submitted(&mut self, field: &Field) {
+      self.0.lock().1.push(field.name().to_string());
+    }
+  }
+
+  fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+      .enable_time()
+      .build()
+      .unwrap()
+      .block_on(future)
+  }
+
+  /// Start an in-memory server sending the given lines, each after reading the client's
+  /// answer to the previous one. Returns the client end and the lines the client sent.
+  fn serve(lines: &[&str]) -> (DuplexStream, JoinHandle<Vec<String>>) {
+    let (client, server) = duplex(1024);
+    let lines: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+
+    let received = tokio::spawn(async move {
+      let mut server = BufReader::new(server);
+      let mut received = vec![];
+
+      for line in lines {
+        server.get_mut().write_all(format!("{}\n", line).as_bytes()).await.unwrap();
+
+        let mut answer = String::new();
+        if server.read_line(&mut answer).await.unwrap() == 0 {
+          break;
+        }
+        received.push(answer.trim_end().to_string());
+      }
+
+      received
+    });
+
+    (client, received)
+  }
+
+  fn session(
+    stream: DuplexStream,
+    solver: fn() -> Res<Option<Vec<u8>>>,
+  ) -> AsyncExasol<StateHelo, DuplexStream> {
+    AsyncExasol::from_stream(stream, Endpoint::new("test", 0)).with_solver(FixedSolver(solver))
+  }
+
+  fn suffix() -> Res<Option<Vec<u8>>> {
+    Ok(Some(b"suffix".to_vec()))
+  }
+
+  fn userdata() -> UserData {
+    serde_json::from_str(
+      r#"{"name": "Jane Doe", "emails": ["jane@example.com"], "birth_date": "01.02.1990",
+          "country": "Germany", "address": ["Street 1"]}"#,
+    )
+    .unwrap()
+  }
+
+  #[test]
+  fn complete_session() {
+    let recorder = Recorder::default();
+    let observer = recorder.clone();
+
+    let received = block_on(async move {
+      let (stream, received) = serve(&["HELO", "POW authdata 1", "NAME abc", "END"]);
+
+      // Spawning the session also checks that its futures can move between threads.
+      let session = tokio::spawn(async move {
+        let session = session(stream, suffix).with_observer(observer);
+        let session = session.handshake().await.map_err(|e| e.to_string())?;
+        let session = session.pow().await.map_err(|e| e.to_string())?;
+        session.submit(&userdata()).await.map(|_| ()).map_err(|e| e.to_string())
+      });
+
+      session.await.unwrap().unwrap();
+      received.await.unwrap()
+    });
+
+    let (sent, fields) = &*recorder.0.lock();
+    assert_eq!(received.len(), 4);
+    assert_eq!(received[..2], ["EHLO", "suffix"]);
+    assert!(received[2].ends_with(" Jane Doe"));
+    assert_eq!(received[3], "OK");
+    assert_eq!(sent, &received);
+    assert_eq!(fields, &["name"]);
+  }
+
+  fn pow_error(solver: fn() -> Res<Option<Vec<u8>>>, cancel: CancelToken) -> Box<dyn Error> {
+    block_on(async move {
+      let (stream, _received) = serve(&["HELO", "POW authdata 1"]);
+      let session = session(stream, solver).with_cancel(cancel).handshake().await.unwrap();
+      session.pow().await.err().unwrap()
+    })
+  }
+
+  #[test]
+  fn cancelled_search_is_not_reported_as_failed() {
+    let cancel = CancelToken::new();
+    cancel.cancel();
+
+    let error = pow_error(|| Ok(None), cancel);
+    assert!(matches!(error.downcast_ref::<Err>(), Some(Err::PowCancelled)), "{}", error);
+
+    let error = pow_error(|| Ok(None), CancelToken::new());
+    assert!(matches!(error.downcast_ref::<Err>(), Some(Err::CannotPow)), "{}", error);
+  }
+
+  #[test]
+  fn solver_error_keeps_its_type() {
+    let error = pow_error(|| Err(Box::new(Err::InvalidDifficulty(99))), CancelToken::new());
+    assert!(matches!(error.downcast_ref::<Err>(), Some(Err::InvalidDifficulty(99))), "{}", error);
+  }
+
+  #[test]
+  fn silent_server_times_out() {
+    let error = block_on(async {
+      let (stream, _server) = duplex(1024);
+      let session = session(stream, suffix).with_read_timeout(Duration::from_millis(300));
+      session.handshake().await.err().unwrap()
+    });
+
+    assert!(matches!(error.downcast_ref::<Err>(), Some(Err::Timeout(..))), "{}", error);
+  }
+}
@@ -0,0 +1,30 @@
+#![warn(clippy::all)]
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A flag shared between a session and whoever may want to stop it (e.g. a signal handler).
+/// Cancelling stops the proof-of-work search and makes the session fail with
+/// [`Err::Cancelled`](crate::error::Err::Cancelled) at the next read from the server.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Request the cancellation of every session holding a clone of this token.
+  pub fn cancel(&self) {
+    self.0.store(true, Ordering::Release);
+  }
+
+  /// Get whether cancellation was requested.
+  pub fn is_cancelled(&self) -> bool {
+    self.0.load(Ordering::Acquire)
+  }
+
+  pub(crate) fn flag(&self) -> &AtomicBool {
+    &self.0
+  }
+}
@@ -0,0 +1,155 @@
+#![warn(clippy::all)]
+
+use crate::identity::Identity;
+use crate::tls::TlsConfig;
+use crate::x509;
+use crate::Res;
+use chrono::{DateTime, Utc};
+use std::fmt;
+
+/// The fields of a certificate as read by the TLS backend.
+pub(crate) struct CertFields {
+  pub(crate) subject: String,
+  pub(crate) issuer: String,
+  pub(crate) not_before: DateTime<Utc>,
+  pub(crate) not_after: DateTime<Utc>,
+}
+
+/// Whether a certificate is valid at a point in time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Validity {
+  /// Valid, and expiring in the given number of days.
+  Valid(i32),
+  /// Expired the given number of days ago.
+  Expired(i32),
+  /// Only valid in the given number of days.
+  NotYetValid(i32),
+}
+
+impl Validity {
+  fn new(not_before: DateTime<Utc>, not_after: DateTime<Utc>) -> Self {
+    let now = Utc::now();
+    let days = |from: DateTime<Utc>, to: DateTime<Utc>| (to - from).num_days() as i32;
+
+    if now < not_before {
+      Validity::NotYetValid(days(now, not_before))
+    } else if now > not_after {
+      Validity::Expired(days(not_after, now))
+    } else {
+      Validity::Valid(days(now, not_after))
+    }
+  }
+
+  /// Get whether the certificate is valid.
+  pub fn is_valid(&self) -> bool {
+    matches!(self, Validity::Valid(_))
+  }
+}
+
+impl fmt::Display for Validity {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Validity::Valid(days) => write!(f, "valid, expires in {} days", days),
+      Validity::Expired(days) => write!(f, "expired {} days ago", days),
+      Validity::NotYetValid(days) => write!(f, "not yet valid, becomes valid in {} days", days),
+    }
+  }
+}
+
+/// The details of a certificate.
+#[derive(Clone, Debug)]
+pub struct CertInfo {
+  subject: String,
+  issuer: String,
+  not_before: String,
+  not_after: String,
+  validity: Validity,
+}
+
+impl CertInfo {
+  /// Read the details of a DER-encoded certificate.
+  pub(crate) fn new(cert: &[u8]) -> Res<Self> {
+    let fields = x509::cert_fields(cert)?;
+
+    Ok(Self {
+      subject: fields.subject,
+      issuer: fields.issuer,
+      not_before: time(fields.not_before),
+      not_after: time(fields.not_after),
+      validity: Validity::new(fields.not_before, fields.not_after),
+    })
+  }
+
+  /// Get a reference to the subject, e.g. `CN=client.exatest.dynu.net`.
+  pub fn subject(&self) -> &str {
+    &self.subject
+  }
+
+  /// Get a reference to the issuer.
+  pub fn issuer(&self) -> &str {
+    &self.issuer
+  }
+
+  /// Get a reference to the start of the validity period.
+  pub fn not_before(&self) -> &str {
+    &self.not_before
+  }
+
+  /// Get a reference to the end of the validity period.
+  pub fn not_after(&self) -> &str {
+    &self.not_after
+  }
+
+  /// Get whether the certificate is valid now.
+  pub fn validity(&self) -> Validity {
+    self.validity
+  }
+}
+
+/// The details of the client certificate, its key and its issuer chain.
+#[derive(Clone, Debug)]
+pub struct IdentityInfo {
+  cert: CertInfo,
+  chain: Vec<CertInfo>,
+  key_type: String,
+  key_matches: bool,
+}
+
+impl IdentityInfo {
+  /// Load the client certificate, key and chain as they would be used for a session.
+  pub fn load(config: &TlsConfig) -> Res<Self> {
+    let identity = Identity::load(config)?;
+
+    Ok(Self {
+      cert: CertInfo::new(identity.cert())?,
+      chain: identity.chain().iter().map(|cert| CertInfo::new(cert)).collect::<Res<_>>()?,
+      key_type: x509::key_type(identity.key())?,
+      key_matches: x509::key_matches(identity.cert(), identity.key())?,
+    })
+  }
+
+  /// Get a reference to the details of the client certificate.
+  pub fn cert(&self) -> &CertInfo {
+    &self.cert
+  }
+
+  /// Get a reference to the details of the issuer certificates.
+  pub fn chain(&self) -> &[CertInfo] {
+    &self.chain
+  }
+
+  /// Get a reference to a description of the private key, e.g. `EC prime256v1 (256 bits)`.
+  pub fn key_type(&self) -> &str {
+    &self.key_type
+  }
+
+  /// Get whether the private key belongs to the client certificate.
+  pub fn key_matches(&self) -> bool {
+    self.key_matches
+  }
+}
+
+/// Format a point in time like OpenSSL prints certificate dates, e.g. `Jan  1 00:00:00 2030 GMT`.
+fn time(time: DateTime<Utc>) -> String {
+  time.format("%b %e %H:%M:%S %Y GMT").to_string()
+}
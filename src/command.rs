@@ -0,0 +1,241 @@
+#![warn(clippy::all)]
+
+use crate::error::Err;
+
+/// A command line sent by the server, parsed into its typed form.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerCommand<'a> {
+  Helo,
+  Pow {
+    authdata: &'a str,
+    difficulty: usize,
+  },
+  Name(&'a str),
+  MailNum(&'a str),
+  /// A MAILx command with its 1-based index.
+  Mail(usize, &'a str),
+  Skype(&'a str),
+  Birthdate(&'a str),
+  Country(&'a str),
+  AddrNum(&'a str),
+  /// An ADDRLINEx command with its 1-based index.
+  AddrLine(usize, &'a str),
+  End,
+  Error(String),
+  /// A data command that is not known to the client.
+  Other {
+    command: &'a str,
+    arg: Option<&'a str>,
+  },
+}
+
+impl<'a> ServerCommand<'a> {
+  /// Parse a line received from the server, without the trailing newline.
+  pub fn parse(line: &'a str) -> Result<Self, Err> {
+    let mut args = line.trim().split(' ');
+    let command = args.next().filter(|command| !command.is_empty()).ok_or(Err::CommandExpected)?;
+
+    let malformed = |reason: String| Err::MalformedCommand(line.trim().to_string(), reason);
+    let mut arg = |what: &str| args.next().ok_or_else(|| malformed(format!("missing {}", what)));
+
+    let parsed = match command {
+      "HELO" => ServerCommand::Helo,
+      "END" => ServerCommand::End,
+      "ERROR" => ServerCommand::Error(line.trim()["ERROR".len()..].trim().to_string()),
+      "POW" => {
+        let authdata = arg("authdata")?;
+        let difficulty = arg("difficulty")?;
+        let difficulty = difficulty
+          .parse::<usize>()
+          .map_err(|_| malformed(format!("invalid difficulty `{}`", difficulty)))?;
+
+        if difficulty > 9 {
+          return Err(Err::InvalidDifficulty(difficulty));
+        }
+
+        ServerCommand::Pow { authdata, difficulty }
+      }
+      "NAME" => ServerCommand::Name(arg("argument")?),
+      "MAILNUM" => ServerCommand::MailNum(arg("argument")?),
+      "SKYPE" => ServerCommand::Skype(arg("argument")?),
+      "BIRTHDATE" => ServerCommand::Birthdate(arg("argument")?),
+      "COUNTRY" => ServerCommand::Country(arg("argument")?),
+      "ADDRNUM" => ServerCommand::AddrNum(arg("argument")?),
+      command if is_indexed(command, "ADDRLINE") => {
+        let index = parse_index(&command["ADDRLINE".len()..]).map_err(malformed)?;
+        ServerCommand::AddrLine(index, arg("argument")?)
+      }
+      command if is_indexed(command, "MAIL") => {
+        let index = parse_index(&command["MAIL".len()..]).map_err(malformed)?;
+        ServerCommand::Mail(index, arg("argument")?)
+      }
+      command if is_malformed_index(command, "ADDRLINE") || is_malformed_index(command, "MAIL") => {
+        return Err(Err::MalformedIndex(command.to_string()));
+      }
+      command => ServerCommand::Other { command, arg: args.next() },
+    };
+
+    Ok(parsed)
+  }
+
+  /// Get the command as it appears on the line, e.g. `MAIL2`.
+  pub fn name(&self) -> String {
+    match self {
+      ServerCommand::Helo => String::from("HELO"),
+      ServerCommand::Pow { .. } => String::from("POW"),
+      ServerCommand::Name(_) => String::from("NAME"),
+      ServerCommand::MailNum(_) => String::from("MAILNUM"),
+      ServerCommand::Mail(index, _) => format!("MAIL{}", index),
+      ServerCommand::Skype(_) => String::from("SKYPE"),
+      ServerCommand::Birthdate(_) => String::from("BIRTHDATE"),
+      ServerCommand::Country(_) => String::from("COUNTRY"),
+      ServerCommand::AddrNum(_) => String::from("ADDRNUM"),
+      ServerCommand::AddrLine(index, _) => format!("ADDRLINE{}", index),
+      ServerCommand::End => String::from("END"),
+      ServerCommand::Error(_) => String::from("ERROR"),
+      ServerCommand::Other { command, .. } => command.to_string(),
+    }
+  }
+
+  /// Get the argument of a data command, which is hashed with the authdata.
+  pub fn arg(&self) -> Option<&'a str> {
+    match *self {
+      ServerCommand::Name(arg)
+      | ServerCommand::MailNum(arg)
+      | ServerCommand::Mail(_, arg)
+      | ServerCommand::Skype(arg)
+      | ServerCommand::Birthdate(arg)
+      | ServerCommand::Country(arg)
+      | ServerCommand::AddrNum(arg)
+      | ServerCommand::AddrLine(_, arg) => Some(arg),
+      ServerCommand::Other { arg, .. } => arg,
+      ServerCommand::Helo | ServerCommand::Pow { .. } | ServerCommand::End | ServerCommand::Error(_) => None,
+    }
+  }
+}
+
+/// Get whether the command is the prefix followed by a numeric index (e.g. `MAIL2`), rather
+/// than another command starting with it (e.g. `MAILINGLIST`), which is left to the field map.
+fn is_indexed(command: &str, prefix: &str) -> bool {
+  command.strip_prefix(prefix).is_some_and(is_index)
+}
+
+/// Get whether the index of an item command is made of digits only, without the sign or
+/// whitespace `usize::from_str` would also accept.
+pub(crate) fn is_index(index: &str) -> bool {
+  !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Get whether the command starts with the prefix but continues with neither a numeric index
+/// nor the rest of another command name (e.g. `MAILx`, `MAIL` or `ADDRLINE1b`).
+fn is_malformed_index(command: &str, prefix: &str) -> bool {
+  command
+    .strip_prefix(prefix)
+    .is_some_and(|rest| !rest.starts_with(|c: char| c.is_ascii_uppercase()))
+}
+
+fn parse_index(index: &str) -> Result<usize, String> {
+  index.parse::<usize>().map_err(|_| format!("index `{}` out of range", index))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rand::Rng;
+
+  #[test]
+  fn parses_commands() {
+    assert_eq!(ServerCommand::parse("HELO").ok(), Some(ServerCommand::Helo));
+    assert_eq!(
+      ServerCommand::parse("POW abc 6\n").ok(),
+      Some(ServerCommand::Pow { authdata: "abc", difficulty: 6 })
+    );
+    assert_eq!(ServerCommand::parse("MAIL12 abc").ok(), Some(ServerCommand::Mail(12, "abc")));
+    assert_eq!(ServerCommand::parse("ADDRLINE2 abc").ok(), Some(ServerCommand::AddrLine(2, "abc")));
+    assert_eq!(
+      ServerCommand::parse("ERROR bad client").ok(),
+      Some(ServerCommand::Error(String::from("bad client")))
+    );
+    assert_eq!(ServerCommand::parse("END").ok(), Some(ServerCommand::End));
+  }
+
+  #[test]
+  fn leaves_other_commands_to_the_field_map() {
+    for (line, command) in [
+      ("MAILINGLIST abc", "MAILINGLIST"),
+      ("MAILINGLIST2 abc", "MAILINGLIST2"),
+      ("ADDRLINES abc", "ADDRLINES"),
+    ] {
+      assert_eq!(ServerCommand::parse(line).ok(), Some(ServerCommand::Other { command, arg: Some("abc") }));
+    }
+  }
+
+  #[test]
+  fn rejects_malformed_indexes() {
+    for (line, command) in [
+      ("MAILx abc", "MAILx"),
+      ("MAIL abc", "MAIL"),
+      ("MAIL+1 abc", "MAIL+1"),
+      ("MAIL-1 abc", "MAIL-1"),
+      ("MAIL1x abc", "MAIL1x"),
+      ("ADDRLINE abc", "ADDRLINE"),
+      ("ADDRLINE1b abc", "ADDRLINE1b"),
+      ("ADDRLINE_2 abc", "ADDRLINE_2"),
+    ] {
+      assert!(
+        matches!(ServerCommand::parse(line), Err(Err::MalformedIndex(ref malformed)) if malformed == command),
+        "{}",
+        line
+      );
+    }
+  }
+
+  #[test]
+  fn rejects_malformed_commands() {
+    assert!(matches!(ServerCommand::parse(""), Err(Err::CommandExpected)));
+    assert!(matches!(ServerCommand::parse("  "), Err(Err::CommandExpected)));
+    assert!(matches!(ServerCommand::parse("POW abc"), Err(Err::MalformedCommand(..))));
+    assert!(matches!(ServerCommand::parse("POW abc x"), Err(Err::MalformedCommand(..))));
+    assert!(matches!(ServerCommand::parse("POW abc 10"), Err(Err::InvalidDifficulty(10))));
+    assert!(matches!(ServerCommand::parse("NAME"), Err(Err::MalformedCommand(..))));
+    assert!(matches!(ServerCommand::parse("MAIL1"), Err(Err::MalformedCommand(..))));
+    assert!(matches!(
+      ServerCommand::parse("MAIL99999999999999999999999 abc"),
+      Err(Err::MalformedCommand(..))
+    ));
+  }
+
+  #[test]
+  fn fuzz_never_panics() {
+    const ALPHABET: &[u8] = b"HELOPWMAINUDRSKYBTCXQ0123456789 \t\n\r-_x";
+    let mut rng = rand::thread_rng();
+
+    for _ in 0..20_000 {
+      let len = rng.gen_range(0..24);
+      let line: String = (0..len).map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char).collect();
+
+      if let Ok(command) = ServerCommand::parse(&line) {
+        let name = command.name();
+        assert!(
+          line.trim().starts_with(&name)
+            || matches!(command, ServerCommand::Mail(..) | ServerCommand::AddrLine(..))
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn fuzz_indexed_commands_round_trip() {
+    let mut rng = rand::thread_rng();
+
+    for _ in 0..10_000 {
+      let index = rng.gen_range(0..100_000usize);
+      let prefix = ["MAIL", "ADDRLINE"][rng.gen_range(0..2)];
+      let line = format!("{}{} arg", prefix, index);
+      let command = ServerCommand::parse(&line).unwrap();
+
+      assert_eq!(command.name(), format!("{}{}", prefix, index));
+      assert_eq!(command.arg(), Some("arg"));
+    }
+  }
+}
@@ -0,0 +1,259 @@
+#![warn(clippy::all)]
+
+use crate::endpoint::Endpoint;
+use crate::fields::{DuplicatePolicy, FieldRule, Policy};
+use crate::proxy::Proxy;
+use crate::tls::{Pin, TlsVersion};
+use crate::Res;
+use log::debug;
+use serde::{Deserialize, Deserializer};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Client settings that can be given in a configuration file instead of on the
+/// command-line. Command-line options take precedence.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+  servers: Vec<Endpoint>,
+  random_order: bool,
+  // Human-readable duration (e.g. "5s", "1m")
+  #[serde(deserialize_with = "deserialize_duration")]
+  connect_timeout: Option<Duration>,
+  // SOCKS5 or HTTP CONNECT proxy to connect through
+  proxy: Option<Proxy>,
+  // Total number of session attempts when a session fails with a transient error
+  attempts: Option<u32>,
+  #[serde(deserialize_with = "deserialize_duration")]
+  retry_backoff: Option<Duration>,
+  #[serde(deserialize_with = "deserialize_duration")]
+  retry_max_backoff: Option<Duration>,
+  // Time the server may stay silent while the client waits for a line
+  #[serde(deserialize_with = "deserialize_duration")]
+  read_timeout: Option<Duration>,
+  // Rules for data commands, taking precedence over the built-in ones
+  fields: Vec<FieldRule>,
+  // How to handle unknown and out-of-order data commands
+  command_policy: Option<Policy>,
+  // How to handle data commands the server sends more than once
+  duplicate_policy: Option<DuplicatePolicy>,
+  // CA bundle to verify the server certificate with
+  ca_file: Option<PathBuf>,
+  // Fingerprint the server certificate must match
+  pin: Option<Pin>,
+  // Accept any server certificate
+  insecure: bool,
+  // Range of TLS versions to negotiate
+  tls_min_version: Option<TlsVersion>,
+  tls_max_version: Option<TlsVersion>,
+  // Cipher suites for TLS 1.2 and older, for TLS 1.3 and key exchange groups to offer
+  cipher_list: Option<String>,
+  ciphersuites: Option<String>,
+  groups: Option<String>,
+  // Name to send (SNI) and to verify the server certificate against
+  server_name: Option<String>,
+}
+
+/// Parse a human-readable duration, or `null` for the default.
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  match Option::<String>::deserialize(deserializer)? {
+    Some(s) => humantime::parse_duration(&s).map(Some).map_err(serde::de::Error::custom),
+    None => Ok(None),
+  }
+}
+
+impl Config {
+  pub fn new(filename: &Path) -> Res<Self> {
+    debug!("Reading configuration from {}:", filename.display());
+
+    let file = File::open(filename)?;
+    let reader = BufReader::new(file);
+    let config: Self = serde_json::from_reader(reader).map_err(Box::new)?;
+
+    debug!("  Servers:");
+    for server in &config.servers {
+      debug!("    {}", server);
+    }
+
+    debug!("  Random order: {}", config.random_order);
+    debug!("  Connect timeout: {:?}", config.connect_timeout);
+    debug!("  Proxy: {:?}", config.proxy.as_ref().map(ToString::to_string));
+
+    debug!("  Attempts: {:?}", config.attempts);
+    debug!("  Retry backoff: {:?}", config.retry_backoff);
+    debug!("  Retry max backoff: {:?}", config.retry_max_backoff);
+    debug!("  Read timeout: {:?}", config.read_timeout);
+    debug!("  Command policy: {:?}", config.command_policy);
+    debug!("  Duplicate policy: {:?}", config.duplicate_policy);
+    debug!("  CA file: {:?}", config.ca_file);
+    debug!("  Pin: {:?}", config.pin.as_ref().map(ToString::to_string));
+    debug!("  Insecure: {}", config.insecure);
+    debug!("  TLS versions: {:?} to {:?}", config.tls_min_version, config.tls_max_version);
+    debug!("  Cipher list: {:?}", config.cipher_list);
+    debug!("  TLS 1.3 cipher suites: {:?}", config.ciphersuites);
+    debug!("  Key exchange groups: {:?}", config.groups);
+    debug!("  Server name: {:?}", config.server_name);
+
+    debug!("  Fields:");
+    for rule in &config.fields {
+      debug!("    {:?}", rule);
+    }
+
+    Ok(config)
+  }
+
+  /// Get a reference to the configured server endpoints.
+  pub fn servers(&self) -> &[Endpoint] {
+    &self.servers
+  }
+
+  /// Get whether endpoints should be tried in random order.
+  pub fn random_order(&self) -> bool {
+    self.random_order
+  }
+
+  /// Get the configured timeout of a single connection attempt.
+  pub fn connect_timeout(&self) -> Option<Duration> {
+    self.connect_timeout
+  }
+
+  /// Get a reference to the configured proxy.
+  pub fn proxy(&self) -> Option<&Proxy> {
+    self.proxy.as_ref()
+  }
+
+  /// Get the configured total number of session attempts.
+  pub fn attempts(&self) -> Option<u32> {
+    self.attempts
+  }
+
+  /// Get the configured delay before the first retry.
+  pub fn retry_backoff(&self) -> Option<Duration> {
+    self.retry_backoff
+  }
+
+  /// Get the configured upper bound of the delay between retries.
+  pub fn retry_max_backoff(&self) -> Option<Duration> {
+    self.retry_max_backoff
+  }
+
+  /// Get the configured time the server may stay silent while the client waits for a line.
+  pub fn read_timeout(&self) -> Option<Duration> {
+    self.read_timeout
+  }
+
+  /// Get a reference to the configured rules for data commands.
+  pub fn fields(&self) -> &[FieldRule] {
+    &self.fields
+  }
+
+  /// Get the configured policy for unknown and out-of-order data commands.
+  pub fn command_policy(&self) -> Option<Policy> {
+    self.command_policy
+  }
+
+  /// Get the configured policy for repeated data commands.
+  pub fn duplicate_policy(&self) -> Option<DuplicatePolicy> {
+    self.duplicate_policy
+  }
+
+  /// Get a reference to the configured CA bundle for verifying the server.
+  pub fn ca_file(&self) -> Option<&Path> {
+    self.ca_file.as_deref()
+  }
+
+  /// Get a reference to the configured server certificate pin.
+  pub fn pin(&self) -> Option<&Pin> {
+    self.pin.as_ref()
+  }
+
+  /// Get whether server certificate verification is disabled.
+  pub fn insecure(&self) -> bool {
+    self.insecure
+  }
+
+  /// Get the configured lowest TLS version.
+  pub fn tls_min_version(&self) -> Option<TlsVersion> {
+    self.tls_min_version
+  }
+
+  /// Get the configured highest TLS version.
+  pub fn tls_max_version(&self) -> Option<TlsVersion> {
+    self.tls_max_version
+  }
+
+  /// Get a reference to the configured cipher suites for TLS 1.2 and older.
+  pub fn cipher_list(&self) -> Option<&str> {
+    self.cipher_list.as_deref()
+  }
+
+  /// Get a reference to the configured TLS 1.3 cipher suites.
+  pub fn ciphersuites(&self) -> Option<&str> {
+    self.ciphersuites.as_deref()
+  }
+
+  /// Get a reference to the configured key exchange groups.
+  pub fn groups(&self) -> Option<&str> {
+    self.groups.as_deref()
+  }
+
+  /// Get a reference to the configured server name.
+  pub fn server_name(&self) -> Option<&str> {
+    self.server_name.as_deref()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(json: &str) -> serde_json::Result<Config> {
+    serde_json::from_str(json)
+  }
+
+  #[test]
+  fn parses_settings() {
+    let config = parse(
+      r#"{"servers": ["staging.example.com:3336", "[::1]:3336"], "random_order": true,
+          "connect_timeout": "5s", "retry_backoff": "500ms", "insecure": true}"#,
+    )
+    .unwrap();
+
+    assert_eq!(
+      config.servers(),
+      [
+        Endpoint::new("staging.example.com", 3336),
+        Endpoint::new("::1", 3336)
+      ]
+    );
+    assert!(config.random_order());
+    assert!(config.insecure());
+    assert_eq!(config.connect_timeout(), Some(Duration::from_secs(5)));
+    assert_eq!(config.retry_backoff(), Some(Duration::from_millis(500)));
+    assert_eq!(config.read_timeout(), None);
+  }
+
+  #[test]
+  fn null_durations_use_the_defaults() {
+    let config = parse(r#"{"connect_timeout": null, "read_timeout": null}"#).unwrap();
+    assert_eq!(config.connect_timeout(), None);
+    assert_eq!(config.read_timeout(), None);
+  }
+
+  #[test]
+  fn rejects_invalid_settings() {
+    for json in [
+      r#"{"connect_timeout": "soon"}"#,
+      r#"{"servers": ["::1:3336"]}"#,
+      r#"{"random_order": "yes"}"#,
+      r#"{"server": "localhost:3336"}"#,
+    ] {
+      assert!(parse(json).is_err(), "{}", json);
+    }
+  }
+}
@@ -0,0 +1,403 @@
+#![warn(clippy::all)]
+
+use crate::cancel::CancelToken;
+use crate::cert_info::CertInfo;
+use crate::endpoint::{self, Endpoint, Endpoints, Order};
+use crate::error::Err;
+use crate::identity::Identity;
+use crate::tls::{is_interruption, TlsConfig, TlsSession, TlsStream};
+use crate::Res;
+use log::debug;
+use std::error::Error;
+use std::io::{self, Read};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+/// How a diagnostic step ended.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+  /// Succeeded.
+  Passed,
+  /// Failed with the given error and an explanation of its likely cause.
+  Failed { error: String, explanation: String },
+  /// Not run, for the given reason.
+  Skipped(String),
+}
+
+/// One step of a connection diagnosis, e.g. connecting to one port.
+#[derive(Clone, Debug)]
+pub struct Step {
+  name: String,
+  elapsed: Duration,
+  outcome: Outcome,
+  details: Vec<String>,
+}
+
+impl Step {
+  fn new(name: String, start: Instant, outcome: Outcome, details: Vec<String>) -> Self {
+    Self { name, elapsed: start.elapsed(), outcome, details }
+  }
+
+  fn skipped(name: String, reason: &str) -> Self {
+    Self::new(name, Instant::now(), Outcome::Skipped(reason.to_string()), vec![])
+  }
+
+  fn failed(name: String, start: Instant, error: &dyn Error, explanation: String) -> Self {
+    let outcome = Outcome::Failed { error: error.to_string(), explanation };
+    Self::new(name, start, outcome, vec![])
+  }
+
+  /// Get a reference to the description of the step.
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  /// Get how long the step took.
+  pub fn elapsed(&self) -> Duration {
+    self.elapsed
+  }
+
+  /// Get a reference to how the step ended.
+  pub fn outcome(&self) -> &Outcome {
+    &self.outcome
+  }
+
+  /// Get a reference to what the step found out, e.g. the addresses a host resolves to.
+  pub fn details(&self) -> &[String] {
+    &self.details
+  }
+}
+
+/// Check the connection to the server step by step: the DNS resolution of the hosts, the TCP
+/// connection to every endpoint, the client certificate, then the TLS handshake with the
+/// first reachable endpoint and whether the server sends HELO. The handshake and HELO are
+/// given the connection timeout. Each step is passed to `report` when it ends. Returns
+/// whether all steps passed.
+pub fn diagnose(
+  config: &TlsConfig,
+  endpoints: &Endpoints,
+  cancel: &CancelToken,
+  mut report: impl FnMut(&Step),
+) -> Res<bool> {
+  let mut passed = true;
+  let mut check = |step: Step| {
+    passed &= !matches!(step.outcome(), Outcome::Failed { .. });
+    report(&step);
+  };
+
+  let unresolved = resolve(endpoints, &mut check);
+
+  let mut reachable = None;
+  for endpoint in endpoints.ordered() {
+    if cancel.is_cancelled() {
+      return Err::cancelled();
+    }
+
+    let name = match endpoints.proxy() {
+      Some(proxy) => format!("TCP connection to {} through proxy {}", endpoint, proxy),
+      None => format!("TCP connection to {}", endpoint),
+    };
+
+    if unresolved.contains(&endpoint.host()) {
+      check(Step::skipped(name, "the host name could not be resolved"));
+      continue;
+    }
+
+    let start = Instant::now();
+    match connect(endpoints, endpoint) {
+      Ok(stream) => {
+        let details = match (endpoints.proxy(), stream.peer_addr()) {
+          (None, Ok(address)) => vec![format!("Connected to {}", address)],
+          _ => vec![],
+        };
+        check(Step::new(name, start, Outcome::Passed, details));
+        reachable.get_or_insert(endpoint);
+      }
+      Err(e) => {
+        let explanation = explain_connect(&*e, endpoints.connect_timeout());
+        check(Step::failed(name, start, &*e, explanation));
+      }
+    }
+  }
+
+  let start = Instant::now();
+  match Identity::load_checked(config) {
+    Ok(identity) => {
+      let cert = CertInfo::new(identity.cert())?;
+      let details = vec![format!("{} ({})", cert.subject(), cert.validity())];
+      check(Step::new("Client certificate".to_string(), start, Outcome::Passed, details));
+    }
+    Err(e) => {
+      let explanation = "The certificate, key or chain cannot be used for a session, the `cert-info` \
+                         subcommand shows them in detail."
+        .to_string();
+      check(Step::failed("Client certificate".to_string(), start, &*e, explanation));
+      check(Step::skipped("TLS handshake".to_string(), "the client certificate cannot be used"));
+      check(Step::skipped("HELO".to_string(), "there is no TLS session"));
+      return Ok(passed);
+    }
+  }
+
+  let endpoint = match reachable {
+    Some(endpoint) => endpoint,
+    None => {
+      check(Step::skipped("TLS handshake".to_string(), "no endpoint is reachable"));
+      check(Step::skipped("HELO".to_string(), "there is no TLS session"));
+      return Ok(passed);
+    }
+  };
+
+  let name = format!("TLS handshake with {}", endpoint);
+  let start = Instant::now();
+  let mut stream = match handshake(config, endpoints, endpoint, cancel) {
+    Ok(stream) => {
+      let (version, cipher) = stream.negotiated();
+      let mut details = vec![format!(
+        "Negotiated {} with cipher suite {}",
+        version, cipher
+      )];
+      for (depth, cert) in stream.peer_chain()?.iter().enumerate() {
+        let cert = CertInfo::new(cert)?;
+        details.push(format!("Server certificate {}: {}", depth, cert.subject()));
+        details.push(format!("  Issuer: {}", cert.issuer()));
+        details.push(format!(
+          "  Valid from {} to {} ({})",
+          cert.not_before(),
+          cert.not_after(),
+          cert.validity()
+        ));
+      }
+      check(Step::new(name, start, Outcome::Passed, details));
+      stream
+    }
+    Err(e) if matches!(e.downcast_ref::<Err>(), Some(Err::Cancelled)) => return Err(e),
+    Err(e) => {
+      let explanation = explain_handshake(&*e);
+      check(Step::failed(name, start, &*e, explanation));
+      check(Step::skipped("HELO".to_string(), "there is no TLS session"));
+      return Ok(passed);
+    }
+  };
+
+  let start = Instant::now();
+  match read_line(&mut stream, endpoints.connect_timeout(), cancel) {
+    Ok(line) if line == "HELO" => check(Step::new("HELO".to_string(), start, Outcome::Passed, vec![])),
+    Ok(line) => {
+      let e = Err::UnknownUnexpectedCommand(line, "HELO".to_string());
+      let explanation = "The server answered with something else than HELO, it may be another service or \
+                         report a problem."
+        .to_string();
+      check(Step::failed("HELO".to_string(), start, &e, explanation));
+    }
+    Err(e) if matches!(e.downcast_ref::<Err>(), Some(Err::Cancelled)) => return Err(e),
+    Err(e) => {
+      let explanation = explain_helo(&*e);
+      check(Step::failed("HELO".to_string(), start, &*e, explanation));
+    }
+  }
+
+  if let Err(e) = stream.close_notify() {
+    debug!("Could not close the TLS session: {}", e);
+  }
+
+  Ok(passed)
+}
+
+/// Resolve the host names of the endpoints, returning the ones that could not be resolved.
+fn resolve(endpoints: &Endpoints, check: &mut impl FnMut(Step)) -> Vec<String> {
+  let mut hosts: Vec<String> = vec![];
+  for endpoint in endpoints.endpoints() {
+    let host = endpoint.host();
+    if matches!(endpoint, Endpoint::Host(..)) && host.parse::<IpAddr>().is_err() && !hosts.contains(&host) {
+      hosts.push(host);
+    }
+  }
+
+  if hosts.is_empty() {
+    check(Step::skipped("DNS resolution".to_string(), "the servers are given by IP address"));
+    return vec![];
+  }
+
+  if let Some(proxy) = endpoints.proxy() {
+    for host in hosts {
+      check(Step::skipped(format!("DNS resolution of {}", host), &format!("resolved by proxy {}", proxy)));
+    }
+    return vec![];
+  }
+
+  let mut unresolved = vec![];
+  for host in hosts {
+    let name = format!("DNS resolution of {}", host);
+    let start = Instant::now();
+
+    match (host.as_str(), 0).to_socket_addrs() {
+      Ok(addresses) => {
+        let details = addresses.map(|address| address.ip().to_string()).collect();
+        check(Step::new(name, start, Outcome::Passed, details));
+      }
+      Err(e) => {
+        let explanation = "The host name cannot be resolved, check its spelling, the DNS settings and \
+                           whether the network is up."
+          .to_string();
+        check(Step::failed(name, start, &e, explanation));
+        unresolved.push(host);
+      }
+    }
+  }
+
+  unresolved
+}
+
+fn connect(endpoints: &Endpoints, endpoint: &Endpoint) -> Res<TcpStream> {
+  match endpoints.proxy() {
+    Some(proxy) => proxy.connect(endpoint, endpoints.connect_timeout()),
+    None => Ok(endpoint::connect_timeout(endpoint, endpoints.connect_timeout())?),
+  }
+}
+
+/// Connect to the endpoint and run the TLS handshake, which may take up to the connection
+/// timeout.
+fn handshake(
+  config: &TlsConfig,
+  endpoints: &Endpoints,
+  endpoint: &Endpoint,
+  cancel: &CancelToken,
+) -> Res<TlsStream> {
+  let timeout = endpoints.connect_timeout();
+  let mut single = Endpoints::new(vec![endpoint.clone()], Order::Sequential, timeout);
+  if let Some(proxy) = endpoints.proxy() {
+    single = single.with_proxy(proxy.clone());
+  }
+
+  let (mut stream, _) = <TlsStream as TlsSession>::connect(config, &single)?;
+  let start = Instant::now();
+
+  while !stream.handshake()? {
+    if cancel.is_cancelled() {
+      return Err::cancelled();
+    }
+
+    if start.elapsed() >= timeout {
+      return Err::timeout("TLS handshake", timeout);
+    }
+  }
+
+  Ok(stream)
+}
+
+/// Read the first line the server sends, waiting up to the timeout.
+fn read_line(stream: &mut TlsStream, timeout: Duration, cancel: &CancelToken) -> Res<String> {
+  let start = Instant::now();
+  let mut line = vec![];
+  let mut byte = [0; 1];
+
+  loop {
+    match stream.read(&mut byte) {
+      Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+      Ok(_) if byte[0] == b'\n' => return Ok(String::from_utf8_lossy(&line).trim_end().to_string()),
+      Ok(_) => line.push(byte[0]),
+      Err(e) if is_interruption(e.kind()) => {}
+      Err(e) => return Err(e.into()),
+    }
+
+    if cancel.is_cancelled() {
+      return Err::cancelled();
+    }
+
+    if start.elapsed() >= timeout {
+      return Err::timeout("HELO from the server", timeout);
+    }
+  }
+}
+
+fn io_error_kind(e: &(dyn Error + 'static)) -> Option<io::ErrorKind> {
+  e.downcast_ref::<io::Error>().map(io::Error::kind)
+}
+
+fn explain_connect(e: &(dyn Error + 'static), timeout: Duration) -> String {
+  if let Some(Err::ProxyFailed(..)) = e.downcast_ref::<Err>() {
+    return "The proxy could not open a tunnel to the server, check the proxy address and credentials and \
+            whether the proxy may connect to this port."
+      .to_string();
+  }
+
+  match io_error_kind(e) {
+    Some(io::ErrorKind::ConnectionRefused) => {
+      "The host refused the connection, the server is not listening on this port or a firewall rejects it."
+        .to_string()
+    }
+    Some(io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock) => format!(
+      "No answer within {}, a firewall probably drops the connection or the host is down. Try another port \
+       or a longer --connect-timeout.",
+      humantime::format_duration(timeout)
+    ),
+    Some(io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable) => {
+      "There is no route to the host, check the network connection of this machine.".to_string()
+    }
+    Some(io::ErrorKind::PermissionDenied) => {
+      "The connection is not permitted, e.g. by a local firewall or security policy.".to_string()
+    }
+    _ => "The connection could not be made, check the network connection of this machine.".to_string(),
+  }
+}
+
+fn explain_handshake(e: &(dyn Error + 'static)) -> String {
+  match e.downcast_ref::<Err>() {
+    Some(Err::ServerVerification(_)) => {
+      return "The server certificate was not accepted, check --ca-file, --pin and --server-name, or use \
+              --insecure to see the certificate the server sends."
+        .to_string();
+    }
+    Some(Err::NoTlsVersion(..) | Err::UnknownTlsName(..)) => {
+      return "The TLS settings are invalid, check the TLS version, cipher suite and group options."
+        .to_string();
+    }
+    Some(Err::Timeout(..)) => {
+      return "The server accepted the connection but did not complete the handshake, this port may not \
+              speak TLS."
+        .to_string();
+    }
+    _ => {}
+  }
+
+  let error = e.to_string().to_lowercase();
+  if error.contains("wrong version number")
+    || error.contains("corrupt message")
+    || error.contains("invalid message")
+  {
+    "The server did not answer with TLS, this port may serve another protocol.".to_string()
+  } else if error.contains("alert") {
+    "The server aborted the handshake, it may not accept the client certificate, or no TLS version, cipher \
+     suite or key exchange group is acceptable to both sides."
+      .to_string()
+  } else if matches!(io_error_kind(e), Some(io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset))
+    || error.contains("unexpected eof")
+  {
+    "The server closed the connection during the handshake, it may not speak TLS on this port or may not \
+     accept the TLS settings."
+      .to_string()
+  } else {
+    "The TLS handshake failed, the server may not speak TLS on this port or may not accept the TLS settings."
+      .to_string()
+  }
+}
+
+fn explain_helo(e: &(dyn Error + 'static)) -> String {
+  if let Some(Err::Timeout(..)) = e.downcast_ref::<Err>() {
+    return "The TLS session was set up but the server sent nothing, it may be overloaded or stuck."
+      .to_string();
+  }
+
+  let error = e.to_string().to_lowercase();
+  if error.contains("alert") || error.contains("certificate") {
+    "The server rejected the session after the handshake, it probably does not accept the client \
+     certificate."
+      .to_string()
+  } else if matches!(io_error_kind(e), Some(io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset)) {
+    "The server closed the connection after the handshake, it probably does not accept the client \
+     certificate."
+      .to_string()
+  } else {
+    "The server could not be read from after the handshake.".to_string()
+  }
+}
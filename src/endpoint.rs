@@ -0,0 +1,305 @@
+#![warn(clippy::all)]
+
+use crate::error::Err;
+use crate::proxy::Proxy;
+use crate::Res;
+use derive_more::Display;
+use log::{debug, info, warn};
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+use serde::{Deserialize, Deserializer};
+use std::io;
+use std::net::{Ipv6Addr, TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Host of the challenge server.
+pub const DEFAULT_HOST: &str = "18.202.148.130";
+
+/// Ports the challenge server is documented to be reachable on.
+pub const DEFAULT_PORTS: &[u16] = &[3336, 8083, 8446, 49155, 3481, 65532];
+
+/// Default timeout for a single connection attempt.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The order in which endpoints are tried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+  Sequential,
+  Random,
+}
+
+/// A server endpoint given as a host (DNS name, IPv4 or IPv6 address) and a port.
+#[derive(Debug, Clone, PartialEq, Eq, Display)]
+pub enum Endpoint {
+  #[display(fmt = "{}:{}", _0, _1)]
+  Host(String, u16),
+  #[display(fmt = "[{}]:{}", _0, _1)]
+  Ipv6(Ipv6Addr, u16),
+}
+
+impl Endpoint {
+  pub fn new(host: &str, port: u16) -> Self {
+    match host.parse::<Ipv6Addr>() {
+      Ok(ip) => Endpoint::Ipv6(ip, port),
+      Err(_) => Endpoint::Host(host.to_string(), port),
+    }
+  }
+
+  /// Get the host part of the endpoint, without brackets for IPv6 addresses.
+  pub fn host(&self) -> String {
+    match self {
+      Endpoint::Host(host, _) => host.clone(),
+      Endpoint::Ipv6(ip, _) => ip.to_string(),
+    }
+  }
+
+  /// Get the port of the endpoint.
+  pub fn port(&self) -> u16 {
+    match self {
+      Endpoint::Host(_, port) | Endpoint::Ipv6(_, port) => *port,
+    }
+  }
+
+  /// Whether the endpoint only resolves to loopback addresses (i.e. a local test server).
+  pub fn is_loopback(&self) -> bool {
+    match (self.host().as_str(), self.port()).to_socket_addrs() {
+      Ok(addresses) => {
+        let addresses: Vec<_> = addresses.collect();
+        !addresses.is_empty() && addresses.iter().all(|address| address.ip().is_loopback())
+      }
+      Err(_) => false,
+    }
+  }
+}
+
+impl FromStr for Endpoint {
+  type Err = Err;
+
+  fn from_str(address: &str) -> Result<Self, Self::Err> {
+    let invalid = |reason| Err::InvalidEndpoint(address.to_string(), reason);
+    let parse_port = |port: &str| port.parse::<u16>().map_err(|_| invalid("invalid port"));
+
+    if let Some(rest) = address.strip_prefix('[') {
+      let (host, port) = rest.split_once("]:").ok_or_else(|| invalid("expecting `[IPV6]:PORT`"))?;
+      let ip = host.parse::<Ipv6Addr>().map_err(|_| invalid("invalid IPv6 address"))?;
+      return Ok(Endpoint::Ipv6(ip, parse_port(port)?));
+    }
+
+    let (host, port) = address.rsplit_once(':').ok_or_else(|| invalid("expecting `HOST:PORT`"))?;
+
+    if host.contains(':') {
+      return Err(invalid("IPv6 addresses must be enclosed in brackets"));
+    }
+
+    if host.is_empty() {
+      return Err(invalid("missing host"));
+    }
+
+    Ok(Endpoint::Host(host.to_string(), parse_port(port)?))
+  }
+}
+
+impl<'de> Deserialize<'de> for Endpoint {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: Deserializer<'de>,
+  {
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+  }
+}
+
+/// Endpoints of the challenge server on all of its documented ports.
+pub fn default_endpoints() -> Vec<Endpoint> {
+  DEFAULT_PORTS.iter().map(|&port| Endpoint::new(DEFAULT_HOST, port)).collect()
+}
+
+#[derive(Clone)]
+pub struct Endpoints {
+  endpoints: Vec<Endpoint>,
+  order: Order,
+  connect_timeout: Duration,
+  proxy: Option<Proxy>,
+}
+
+impl Default for Endpoints {
+  fn default() -> Self {
+    Self::new(default_endpoints(), Order::Sequential, DEFAULT_CONNECT_TIMEOUT)
+  }
+}
+
+impl Endpoints {
+  pub fn new(endpoints: Vec<Endpoint>, order: Order, connect_timeout: Duration) -> Self {
+    Self { endpoints, order, connect_timeout, proxy: None }
+  }
+
+  /// Connect to the endpoints through the given proxy.
+  pub fn with_proxy(mut self, proxy: Proxy) -> Self {
+    self.proxy = Some(proxy);
+    self
+  }
+
+  /// Get a reference to the list of endpoints.
+  pub fn endpoints(&self) -> &[Endpoint] {
+    &self.endpoints
+  }
+
+  /// Get the order in which endpoints are tried.
+  pub fn order(&self) -> Order {
+    self.order
+  }
+
+  /// Get the timeout of a single connection attempt.
+  pub fn connect_timeout(&self) -> Duration {
+    self.connect_timeout
+  }
+
+  /// Get a reference to the proxy the endpoints are connected to through.
+  pub fn proxy(&self) -> Option<&Proxy> {
+    self.proxy.as_ref()
+  }
+
+  /// Get the endpoints in the order they should be tried in.
+  pub(crate) fn ordered(&self) -> Vec<&Endpoint> {
+    let mut endpoints: Vec<&Endpoint> = self.endpoints.iter().collect();
+
+    if self.order == Order::Random {
+      endpoints.shuffle(&mut thread_rng());
+    }
+
+    endpoints
+  }
+
+  /// Connect to the first reachable endpoint, returning the stream and the endpoint that
+  /// worked.
+  pub(crate) fn connect(&self) -> Res<(TcpStream, Endpoint)> {
+    for endpoint in self.ordered() {
+      debug!("Connecting to {} (timeout {:?})", endpoint, self.connect_timeout);
+
+      let stream = match &self.proxy {
+        Some(proxy) => proxy.connect(endpoint, self.connect_timeout),
+        None => connect_timeout(endpoint, self.connect_timeout).map_err(Into::into),
+      };
+
+      match stream {
+        Ok(stream) => {
+          info!("Connected to {}", endpoint);
+          return Ok((stream, endpoint.clone()));
+        }
+        Err(e) => warn!("Could not connect to {}: {}", endpoint, e),
+      }
+    }
+
+    Err::no_reachable_endpoint(self.endpoints.len())
+  }
+}
+
+pub(crate) fn connect_timeout(endpoint: &Endpoint, timeout: Duration) -> io::Result<TcpStream> {
+  let mut last_error = None;
+
+  for socket_address in (endpoint.host().as_str(), endpoint.port()).to_socket_addrs()? {
+    debug!("Trying {} ({})", socket_address, endpoint);
+
+    match TcpStream::connect_timeout(&socket_address, timeout) {
+      Ok(stream) => return Ok(stream),
+      Err(e) => last_error = Some(e),
+    }
+  }
+
+  Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Host did not resolve")))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::net::TcpListener;
+
+  fn listener() -> (TcpListener, Endpoint) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    (listener, Endpoint::new("127.0.0.1", port))
+  }
+
+  /// Get an endpoint nothing listens on, from a listener that is closed again.
+  fn closed() -> Endpoint {
+    listener().1
+  }
+
+  fn endpoints(endpoints: Vec<Endpoint>, order: Order) -> Endpoints {
+    Endpoints::new(endpoints, order, Duration::from_secs(2))
+  }
+
+  #[test]
+  fn parses_addresses() {
+    assert_eq!("example.com:3336".parse::<Endpoint>().unwrap(), Endpoint::new("example.com", 3336));
+
+    let ipv6 = "[::1]:3336".parse::<Endpoint>().unwrap();
+    assert_eq!(ipv6, Endpoint::new("::1", 3336));
+    assert_eq!(ipv6.host(), "::1");
+    assert_eq!(ipv6.to_string(), "[::1]:3336");
+
+    for (address, expected) in [
+      ("example.com", "expecting `HOST:PORT`"),
+      ("example.com:", "invalid port"),
+      ("example.com:65536", "invalid port"),
+      (":3336", "missing host"),
+      ("::1:3336", "IPv6 addresses must be enclosed in brackets"),
+      ("[::1]", "expecting `[IPV6]:PORT`"),
+      ("[::1]:", "invalid port"),
+      ("[example.com]:3336", "invalid IPv6 address"),
+    ] {
+      match address.parse::<Endpoint>() {
+        Err(Err::InvalidEndpoint(_, reason)) => assert_eq!(reason, expected, "{}", address),
+        res => panic!("expected an invalid endpoint for `{}`, got {:?}", address, res),
+      }
+    }
+  }
+
+  #[test]
+  fn connects_to_first_endpoint() {
+    let (_first, first) = listener();
+    let (_second, second) = listener();
+
+    let (_, endpoint) = endpoints(vec![first.clone(), second], Order::Sequential).connect().unwrap();
+    assert_eq!(endpoint, first);
+  }
+
+  #[test]
+  fn fails_over_to_next_endpoint() {
+    let (listener, open) = listener();
+
+    let (stream, endpoint) = endpoints(vec![closed(), closed(), open.clone()], Order::Sequential)
+      .connect()
+      .unwrap();
+    assert_eq!(endpoint, open);
+    assert_eq!(stream.peer_addr().unwrap(), listener.local_addr().unwrap());
+  }
+
+  #[test]
+  fn fails_over_in_random_order() {
+    let (_listener, open) = listener();
+
+    for _ in 0..10 {
+      let (_, endpoint) = endpoints(vec![closed(), open.clone(), closed()], Order::Random)
+        .connect()
+        .unwrap();
+      assert_eq!(endpoint, open);
+    }
+  }
+
+  #[test]
+  fn fails_without_reachable_endpoint() {
+    let error = endpoints(vec![closed(), closed()], Order::Sequential).connect().unwrap_err();
+    assert!(matches!(error.downcast_ref::<Err>(), Some(Err::NoReachableEndpoint(2))), "{}", error);
+  }
+
+  #[test]
+  fn tries_every_endpoint_in_random_order() {
+    let all: Vec<Endpoint> = (1..=5).map(|port| Endpoint::new("127.0.0.1", port)).collect();
+    let mut ordered: Vec<Endpoint> =
+      endpoints(all.clone(), Order::Random).ordered().into_iter().cloned().collect();
+    ordered.sort_by_key(Endpoint::port);
+    assert_eq!(ordered, all);
+  }
+}
@@ -1,61 +1,206 @@
 #![warn(clippy::all)]
 
+use crate::proxy::Proxy;
 use crate::Res;
 use derive_more::Display;
 use std::error::Error;
+use std::path::Path;
+use std::time::Duration;
 
 #[derive(Debug, Display)]
-#[display(fmt = "{}")]
 pub enum Err {
   #[display(fmt = "Expecting a command")]
   CommandExpected,
-  #[display(fmt = "Server error: {}", _0)]
-  Server(String),
+  #[display(fmt = "Server error in {} state: {}", _0, _1)]
+  Server(&'static str, String),
+  #[display(fmt = "Server ended the session in {} state", _0)]
+  ServerEnded(&'static str),
   #[display(fmt = "Unknown or unexpected command `{}`, expecting `{}`", _0, _1)]
   UnknownUnexpectedCommand(String, String),
-  #[display(fmt = "Missing argument")]
-  MissingArg,
+  #[display(fmt = "Malformed command `{}`: {}", _0, _1)]
+  MalformedCommand(String, String),
+  #[display(fmt = "Malformed index in command `{}`, expecting digits only", _0)]
+  MalformedIndex(String),
   #[display(fmt = "Invalid difficulty `{}`", _0)]
   InvalidDifficulty(usize),
   #[display(fmt = "Could not find a random string")]
   CannotPow,
-  #[display(fmt = "Server must first request MAILNUM")]
-  NoMailNum,
-  #[display(fmt = "Server must first request ADDRNUM")]
-  NoAddrNum,
-  #[display(fmt = "Invalid mail index `{}` request", _0)]
-  InvalidMailIndex(usize),
-  #[display(fmt = "Invalid address line index `{}` request", _0)]
-  InvalidAddressIndex(usize),
+  #[display(fmt = "Random string search was cancelled")]
+  PowCancelled,
+  #[display(fmt = "Session was cancelled")]
+  Cancelled,
+  #[display(fmt = "Server must first request {}", _0)]
+  NoCount(String),
+  #[display(fmt = "Invalid {}x index `{}` request", _0, _1)]
+  InvalidIndex(String, usize),
+  #[display(fmt = "Invalid policy `{}`, expecting `strict`, `lenient` or `answer-na`", _0)]
+  InvalidPolicy(String),
+  #[display(fmt = "Invalid duplicate policy `{}`, expecting `warn` or `reject`", _0)]
+  InvalidDuplicatePolicy(String),
+  #[display(fmt = "Server requested {} more than once", _0)]
+  DuplicateCommand(String),
+  #[display(fmt = "User data has no `{}` field", _0)]
+  MissingField(String),
+  #[display(fmt = "User data field `{}` is not {}", _0, _1)]
+  InvalidFieldType(String, &'static str),
+  #[display(fmt = "Could not connect to any of the {} endpoints", _0)]
+  NoReachableEndpoint(usize),
+  #[display(fmt = "Invalid endpoint `{}`: {}", _0, _1)]
+  InvalidEndpoint(String, &'static str),
+  #[display(fmt = "Invalid pin `{}`: {}", _0, _1)]
+  InvalidPin(String, &'static str),
+  #[display(fmt = "Invalid proxy `{}`: {}", _0, _1)]
+  InvalidProxy(String, &'static str),
+  #[display(fmt = "Proxy {} failed: {}", _0, _1)]
+  ProxyFailed(String, String),
+  #[display(fmt = "Invalid TLS version `{}`, expecting `1.0`, `1.1`, `1.2` or `1.3`", _0)]
+  InvalidTlsVersion(String),
+  #[display(fmt = "No supported TLS version between {} and {}", _0, _1)]
+  NoTlsVersion(String, String),
+  #[display(fmt = "Unknown or unsupported {} `{}`", _0, _1)]
+  UnknownTlsName(&'static str, String),
+  #[display(fmt = "{} is not a PEM, DER or PKCS#12 file", _0)]
+  UnknownFormat(String),
+  #[display(fmt = "No certificate found in {}", _0)]
+  NoCertificate(String),
+  #[display(fmt = "No private key found in {}", _0)]
+  NoPrivateKey(String),
+  #[display(fmt = "The private key does not belong to the client certificate {}", _0)]
+  KeyMismatch(String),
+  #[display(fmt = "{} is encrypted, but no passphrase was given", _0)]
+  NoPassphrase(String),
+  #[display(fmt = "Could not decrypt {}: wrong passphrase", _0)]
+  WrongPassphrase(String),
+  #[display(fmt = "{} holds a legacy encrypted PEM key, convert it with `openssl pkcs8 -topk8`", _0)]
+  LegacyEncryptedKey(String),
+  #[display(fmt = "Environment variable `{}` is not set", _0)]
+  MissingEnv(String),
+  #[display(fmt = "Client certificate {} expired on {}", _0, _1)]
+  CertificateExpired(String, String),
+  #[display(fmt = "Client certificate {} is not valid before {}", _0, _1)]
+  CertificateNotYetValid(String, String),
+  #[display(fmt = "Certificate `{}` has no issuer certificates to verify the server with", _0)]
+  NoIssuerChain(String),
+  #[display(fmt = "No {} within {}", _0, _1)]
+  Timeout(&'static str, String),
+  #[display(fmt = "Server certificate verification failed: {}", _0)]
+  ServerVerification(String),
+  #[display(fmt = "Replay mismatch on sent line {}: expected `{}`, got `{}`", _0, _1, _2)]
+  ReplayMismatch(usize, String, String),
+  #[display(fmt = "Replay sent line {} is not a valid POW suffix: `{}`", _0, _1)]
+  ReplayInvalidPow(usize, String),
+  #[display(fmt = "Replay has no recorded POW suffix for authdata `{}`", _0)]
+  ReplayNoPow(String),
 }
 
 impl Err {
-  pub(crate) fn server<T>(msg: String) -> Res<T> {
-    Err(Box::new(Err::Server(msg)))
+  pub(crate) fn server<T>(state: &'static str, msg: String) -> Res<T> {
+    Err(Box::new(Err::Server(state, msg)))
+  }
+
+  pub(crate) fn server_ended<T>(state: &'static str) -> Res<T> {
+    Err(Box::new(Err::ServerEnded(state)))
   }
 
   pub(crate) fn unknown_or_unexpected_command<T>(command: &str, expected: &str) -> Res<T> {
     Err(Box::new(Err::UnknownUnexpectedCommand(command.to_string(), expected.to_string())))
   }
 
-  pub(crate) fn invalid_difficulty<T>(difficulty: usize) -> Res<T> {
-    Err(Box::new(Err::InvalidDifficulty(difficulty)))
+  pub(crate) fn cancelled<T>() -> Res<T> {
+    Err(Box::new(Err::Cancelled))
+  }
+
+  pub(crate) fn no_count<T>(count: &str) -> Res<T> {
+    Err(Box::new(Err::NoCount(count.to_string())))
+  }
+
+  pub(crate) fn invalid_index<T>(item: &str, index: usize) -> Res<T> {
+    Err(Box::new(Err::InvalidIndex(item.to_string(), index)))
+  }
+
+  pub(crate) fn duplicate_command<T>(command: &str) -> Res<T> {
+    Err(Box::new(Err::DuplicateCommand(command.to_string())))
+  }
+
+  pub(crate) fn missing_field<T>(field: &str) -> Res<T> {
+    Err(Box::new(Err::MissingField(field.to_string())))
+  }
+
+  pub(crate) fn invalid_field_type<T>(field: &str, expected: &'static str) -> Res<T> {
+    Err(Box::new(Err::InvalidFieldType(field.to_string(), expected)))
+  }
+
+  pub(crate) fn no_reachable_endpoint<T>(endpoints: usize) -> Res<T> {
+    Err(Box::new(Err::NoReachableEndpoint(endpoints)))
+  }
+
+  pub(crate) fn unknown_format<T>(origin: &str) -> Res<T> {
+    Err(Box::new(Err::UnknownFormat(origin.to_string())))
+  }
+
+  pub(crate) fn no_certificate<T>(origin: &str) -> Res<T> {
+    Err(Box::new(Err::NoCertificate(origin.to_string())))
+  }
+
+  pub(crate) fn no_private_key<T>(origin: &str) -> Res<T> {
+    Err(Box::new(Err::NoPrivateKey(origin.to_string())))
+  }
+
+  pub(crate) fn proxy<T>(proxy: &Proxy, reason: &str) -> Res<T> {
+    Err(Box::new(Err::ProxyFailed(proxy.to_string(), reason.to_string())))
+  }
+
+  pub(crate) fn no_tls_version<T>(min: &str, max: &str) -> Res<T> {
+    Err(Box::new(Err::NoTlsVersion(min.to_string(), max.to_string())))
+  }
+
+  pub(crate) fn unknown_tls_name<T>(kind: &'static str, name: &str) -> Res<T> {
+    Err(Box::new(Err::UnknownTlsName(kind, name.to_string())))
+  }
+
+  pub(crate) fn key_mismatch<T>(subject: &str) -> Res<T> {
+    Err(Box::new(Err::KeyMismatch(subject.to_string())))
+  }
+
+  pub(crate) fn no_passphrase<T>(origin: &str) -> Res<T> {
+    Err(Box::new(Err::NoPassphrase(origin.to_string())))
+  }
+
+  pub(crate) fn wrong_passphrase<T>(origin: &str) -> Res<T> {
+    Err(Box::new(Err::WrongPassphrase(origin.to_string())))
+  }
+
+  #[cfg(feature = "rustls")]
+  pub(crate) fn legacy_encrypted_key<T>(origin: &str) -> Res<T> {
+    Err(Box::new(Err::LegacyEncryptedKey(origin.to_string())))
+  }
+
+  pub(crate) fn missing_env<T>(var: &str) -> Res<T> {
+    Err(Box::new(Err::MissingEnv(var.to_string())))
+  }
+
+  pub(crate) fn certificate_expired<T>(subject: &str, not_after: &str) -> Res<T> {
+    Err(Box::new(Err::CertificateExpired(subject.to_string(), not_after.to_string())))
+  }
+
+  pub(crate) fn certificate_not_yet_valid<T>(subject: &str, not_before: &str) -> Res<T> {
+    Err(Box::new(Err::CertificateNotYetValid(subject.to_string(), not_before.to_string())))
   }
 
-  pub(crate) fn no_mailnum<T>() -> Res<T> {
-    Err(Box::new(Err::NoMailNum))
+  pub(crate) fn no_issuer_chain<T>(cert_file: &Path) -> Res<T> {
+    Err(Box::new(Err::NoIssuerChain(cert_file.display().to_string())))
   }
 
-  pub(crate) fn no_addrnum<T>() -> Res<T> {
-    Err(Box::new(Err::NoAddrNum))
+  pub(crate) fn timeout<T>(what: &'static str, timeout: Duration) -> Res<T> {
+    Err(Box::new(Err::Timeout(what, humantime::format_duration(timeout).to_string())))
   }
 
-  pub(crate) fn invalid_mail_index<T>(index: usize) -> Res<T> {
-    Err(Box::new(Err::InvalidMailIndex(index)))
+  pub(crate) fn server_verification<T>(reason: &str) -> Res<T> {
+    Err(Box::new(Err::ServerVerification(reason.to_string())))
   }
 
-  pub(crate) fn invalid_address_index<T>(index: usize) -> Res<T> {
-    Err(Box::new(Err::InvalidAddressIndex(index)))
+  pub(crate) fn replay_no_pow<T>(authdata: &str) -> Res<T> {
+    Err(Box::new(Err::ReplayNoPow(authdata.to_string())))
   }
 }
 
@@ -0,0 +1,581 @@
+#![warn(clippy::all)]
+
+use crate::command::is_index;
+use crate::error::Err;
+use crate::userdata::{UserData, Value};
+use crate::Res;
+use chrono::NaiveDate;
+use log::warn;
+use serde::{Deserialize, Deserializer};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+/// A function applied to a user data value before it is sent.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+  /// Send the value unchanged.
+  #[default]
+  Plain,
+  Uppercase,
+  Lowercase,
+  /// Reformat a %d.%m.%Y date using the given chrono format string.
+  Date(String),
+}
+
+impl Format {
+  fn apply<'u>(&self, value: Cow<'u, str>) -> Res<Cow<'u, str>> {
+    let value = match self {
+      Format::Plain => value,
+      Format::Uppercase => Cow::from(value.to_uppercase()),
+      Format::Lowercase => Cow::from(value.to_lowercase()),
+      Format::Date(format) => {
+        let date = NaiveDate::parse_from_str(&value, "%d.%m.%Y")?;
+        Cow::from(date.format(format).to_string())
+      }
+    };
+
+    Ok(value)
+  }
+}
+
+/// A rule mapping server data commands to a user data field.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum FieldRule {
+  /// A command answered with a single value, e.g. NAME.
+  Scalar {
+    command: String,
+    field: String,
+    #[serde(default)]
+    format: Format,
+    /// Sent when the user data does not have the field.
+    #[serde(default)]
+    default: Option<String>,
+    /// Whether the server may end the session without asking for the field.
+    #[serde(default)]
+    optional: bool,
+  },
+  /// A list answered with its length for the count command (e.g. MAILNUM) and with its
+  /// items for the indexed item commands (e.g. MAIL1, MAIL2), which are 1-based.
+  List {
+    count: String,
+    item: String,
+    field: String,
+    #[serde(default)]
+    format: Format,
+    #[serde(default)]
+    optional: bool,
+  },
+}
+
+impl FieldRule {
+  fn scalar(command: &str, field: &str) -> Self {
+    FieldRule::Scalar {
+      command: command.to_string(),
+      field: field.to_string(),
+      format: Format::Plain,
+      default: None,
+      optional: false,
+    }
+  }
+
+  fn list(count: &str, item: &str, field: &str) -> Self {
+    FieldRule::List {
+      count: count.to_string(),
+      item: item.to_string(),
+      field: field.to_string(),
+      format: Format::Plain,
+      optional: false,
+    }
+  }
+
+  /// Get the commands the server must send for the rule to be complete, e.g. MAILNUM, MAIL1
+  /// and MAIL2 for two email addresses.
+  fn required_commands(&self, userdata: &UserData) -> Vec<String> {
+    match self {
+      FieldRule::Scalar { optional: true, .. } | FieldRule::List { optional: true, .. } => vec![],
+      FieldRule::Scalar { command, .. } => vec![command.clone()],
+      FieldRule::List { count, item, field, .. } => {
+        let len = match userdata.value(field) {
+          Some(Value::List(list)) => list.len(),
+          _ => 0,
+        };
+
+        std::iter::once(count.clone())
+          .chain((1..=len).map(|index| format!("{}{}", item, index)))
+          .collect()
+      }
+    }
+  }
+
+  /// Get the commands the rule answers, e.g. `MAILNUM|MAILx`.
+  fn commands(&self) -> String {
+    match self {
+      FieldRule::Scalar { command, .. } => command.clone(),
+      FieldRule::List { count, item, .. } => format!("{}|{}x", count, item),
+    }
+  }
+}
+
+/// How to handle data commands that no rule answers (e.g. a new optional field) and item
+/// commands that arrive before their count command.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Policy {
+  /// Abort the session.
+  #[default]
+  Strict,
+  /// Log and skip unknown commands and item commands sent before their count command
+  /// without answering them.
+  Lenient,
+  /// Like `Lenient`, but answer unknown commands with N/A. Item commands sent before their
+  /// count command are still skipped, as N/A would pass for an item of the list.
+  AnswerNa,
+}
+
+impl Display for Policy {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Policy::Strict => write!(f, "strict"),
+      Policy::Lenient => write!(f, "lenient"),
+      Policy::AnswerNa => write!(f, "answer-na"),
+    }
+  }
+}
+
+impl FromStr for Policy {
+  type Err = Err;
+
+  fn from_str(policy: &str) -> Result<Self, Self::Err> {
+    match policy {
+      "strict" => Ok(Policy::Strict),
+      "lenient" => Ok(Policy::Lenient),
+      "answer-na" => Ok(Policy::AnswerNa),
+      _ => Err(Err::InvalidPolicy(policy.to_string())),
+    }
+  }
+}
+
+impl<'de> Deserialize<'de> for Policy {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: Deserializer<'de>,
+  {
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+  }
+}
+
+/// How to handle a data command the server already sent in the same session.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+  /// Log a warning and answer the command again.
+  #[default]
+  Warn,
+  /// Abort the session.
+  Reject,
+}
+
+impl Display for DuplicatePolicy {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      DuplicatePolicy::Warn => write!(f, "warn"),
+      DuplicatePolicy::Reject => write!(f, "reject"),
+    }
+  }
+}
+
+impl FromStr for DuplicatePolicy {
+  type Err = Err;
+
+  fn from_str(policy: &str) -> Result<Self, Self::Err> {
+    match policy {
+      "warn" => Ok(DuplicatePolicy::Warn),
+      "reject" => Ok(DuplicatePolicy::Reject),
+      _ => Err(Err::InvalidDuplicatePolicy(policy.to_string())),
+    }
+  }
+}
+
+impl<'de> Deserialize<'de> for DuplicatePolicy {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: Deserializer<'de>,
+  {
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+  }
+}
+
+/// The table of rules used to answer the data commands of the server.
+#[derive(Clone, Debug)]
+pub struct FieldMap {
+  rules: Vec<FieldRule>,
+  policy: Policy,
+  duplicates: DuplicatePolicy,
+}
+
+impl Default for FieldMap {
+  fn default() -> Self {
+    Self::builtin()
+  }
+}
+
+impl FieldMap {
+  /// The data commands of the challenge as published.
+  pub fn builtin() -> Self {
+    let mut skype = FieldRule::scalar("SKYPE", "skype");
+    if let FieldRule::Scalar { default, .. } = &mut skype {
+      *default = Some(String::from("N/A"));
+    }
+
+    let rules = vec![
+      FieldRule::scalar("NAME", "name"),
+      FieldRule::list("MAILNUM", "MAIL", "emails"),
+      skype,
+      FieldRule::scalar("BIRTHDATE", "birth_date"),
+      FieldRule::scalar("COUNTRY", "country"),
+      FieldRule::list("ADDRNUM", "ADDRLINE", "address"),
+    ];
+
+    Self { rules, policy: Policy::Strict, duplicates: DuplicatePolicy::Warn }
+  }
+
+  /// Add rules in front of the table, so they take precedence over the existing ones.
+  pub fn with_rules(mut self, rules: impl IntoIterator<Item = FieldRule>) -> Self {
+    let mut rules: Vec<FieldRule> = rules.into_iter().collect();
+    rules.append(&mut self.rules);
+    self.rules = rules;
+    self
+  }
+
+  /// Handle unknown and out-of-order commands according to the given policy.
+  pub fn with_policy(mut self, policy: Policy) -> Self {
+    self.policy = policy;
+    self
+  }
+
+  /// Handle repeated data commands according to the given policy.
+  pub fn with_duplicates(mut self, duplicates: DuplicatePolicy) -> Self {
+    self.duplicates = duplicates;
+    self
+  }
+
+  /// Get a reference to the rules, in order of precedence.
+  pub fn rules(&self) -> &[FieldRule] {
+    &self.rules
+  }
+
+  /// Get the policy for unknown and out-of-order commands.
+  pub fn policy(&self) -> Policy {
+    self.policy
+  }
+
+  /// Get the policy for repeated data commands.
+  pub fn duplicates(&self) -> DuplicatePolicy {
+    self.duplicates
+  }
+
+  /// Check the data commands requested in a session against the required ones.
+  pub(crate) fn report(&self, requested: Vec<String>, answered: Vec<String>, userdata: &UserData) -> Report {
+    let mut missing = vec![];
+
+    for rule in &self.rules {
+      for command in rule.required_commands(userdata) {
+        if !requested.contains(&command) && !missing.contains(&command) {
+          missing.push(command);
+        }
+      }
+    }
+
+    let mut duplicates = vec![];
+
+    for (index, command) in requested.iter().enumerate() {
+      if requested[..index].contains(command) && !duplicates.contains(command) {
+        duplicates.push(command.clone());
+      }
+    }
+
+    Report { requested, answered, missing, duplicates }
+  }
+
+  /// Find the name and value of the user data field a command asks for, or `None` if the
+  /// command should be skipped. `counted` holds the lists whose count command was already
+  /// answered.
+  pub(crate) fn resolve<'u>(
+    &self,
+    command: &str,
+    counted: &mut HashSet<String>,
+    userdata: &'u UserData,
+  ) -> Res<Option<(String, Cow<'u, str>)>> {
+    for rule in &self.rules {
+      match rule {
+        FieldRule::Scalar { command: name, field, format, default, .. } if name == command => {
+          let value = match userdata.value(field) {
+            Some(Value::Text(text)) => text,
+            Some(Value::List(_)) => return Err::invalid_field_type(field, "a single value"),
+            None => match default {
+              Some(default) => Cow::from(default.clone()),
+              None => return Err::missing_field(field),
+            },
+          };
+
+          return Ok(Some((field.clone(), format.apply(value)?)));
+        }
+        FieldRule::Scalar { .. } => {}
+        FieldRule::List { count, item, field, format, .. } => {
+          if count == command {
+            let len = list(userdata, field)?.len();
+            counted.insert(field.clone());
+            return Ok(Some((field.clone(), Cow::from(len.to_string()))));
+          }
+
+          let index = match command
+            .strip_prefix(item.as_str())
+            .filter(|index| is_index(index))
+            .and_then(|index| index.parse::<usize>().ok())
+          {
+            Some(index) => index,
+            None => continue,
+          };
+
+          if !counted.contains(field) {
+            if self.policy == Policy::Strict {
+              return Err::no_count(count);
+            }
+
+            warn!("Skipping {} as the server did not request {} first", command, count);
+            return Ok(None);
+          }
+
+          let list = list(userdata, field)?;
+
+          if index < 1 || index > list.len() {
+            return Err::invalid_index(item, index);
+          }
+
+          let value = format.apply(Cow::from(list[index - 1].as_str()))?;
+          return Ok(Some((format!("{}[{}]", field, index - 1), value)));
+        }
+      }
+    }
+
+    match self.policy {
+      Policy::Strict => {
+        let expected: Vec<String> = self.rules.iter().map(FieldRule::commands).collect();
+        Err::unknown_or_unexpected_command(command, &expected.join("|"))
+      }
+      Policy::Lenient => {
+        warn!("Skipping unknown command {}", command);
+        Ok(None)
+      }
+      Policy::AnswerNa => {
+        warn!("Answering unknown command {} with N/A", command);
+        Ok(Some((command.to_lowercase(), Cow::from("N/A"))))
+      }
+    }
+  }
+}
+
+fn list<'u>(userdata: &'u UserData, field: &str) -> Res<&'u [String]> {
+  match userdata.value(field) {
+    Some(Value::List(list)) => Ok(list),
+    Some(Value::Text(_)) => Err::invalid_field_type(field, "a list"),
+    None => Err::missing_field(field),
+  }
+}
+
+/// The completeness report of the data phase of a session, produced when the server sends
+/// END.
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+  requested: Vec<String>,
+  answered: Vec<String>,
+  missing: Vec<String>,
+  duplicates: Vec<String>,
+}
+
+impl Report {
+  /// Get a reference to the data commands the server sent, in order.
+  pub fn requested(&self) -> &[String] {
+    &self.requested
+  }
+
+  /// Get a reference to the names of the user data fields that were answered, in order.
+  pub fn answered(&self) -> &[String] {
+    &self.answered
+  }
+
+  /// Get a reference to the required data commands the server never sent.
+  pub fn missing(&self) -> &[String] {
+    &self.missing
+  }
+
+  /// Get a reference to the data commands the server sent more than once.
+  pub fn duplicates(&self) -> &[String] {
+    &self.duplicates
+  }
+
+  /// Get whether the server asked for every required field exactly once.
+  pub fn is_complete(&self) -> bool {
+    self.missing.is_empty() && self.duplicates.is_empty()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::Config;
+
+  const USERDATA: &str = r#"{"name": "Jane Doe", "emails": ["jane@example.com", "jd@example.com"],
+    "birth_date": "01.02.1990", "country": "Germany", "address": ["Street 1"], "phone": "+49 123",
+    "links": ["HTTPS://EXAMPLE.COM/A", "https://example.com/b"]}"#;
+
+  /// A resolved field name and value, or `None` for a skipped command.
+  type Resolved = Option<(String, String)>;
+
+  /// Resolve the commands in order against the user data.
+  fn resolve_all(fields: &FieldMap, commands: &[&str]) -> Vec<Res<Resolved>> {
+    let userdata: UserData = serde_json::from_str(USERDATA).unwrap();
+    let mut counted = HashSet::new();
+
+    commands
+      .iter()
+      .map(|command| {
+        let resolved = fields.resolve(command, &mut counted, &userdata)?;
+        Ok(resolved.map(|(field, value)| (field, value.into_owned())))
+      })
+      .collect()
+  }
+
+  fn answered(field: &str, value: &str) -> Resolved {
+    Some((field.to_string(), value.to_string()))
+  }
+
+  #[test]
+  fn resolves_builtin_commands() {
+    let table = [
+      ("NAME", answered("name", "Jane Doe")),
+      ("MAILNUM", answered("emails", "2")),
+      ("MAIL1", answered("emails[0]", "jane@example.com")),
+      ("MAIL2", answered("emails[1]", "jd@example.com")),
+      ("MAIL02", answered("emails[1]", "jd@example.com")),
+      ("SKYPE", answered("skype", "N/A")),
+      ("BIRTHDATE", answered("birth_date", "01.02.1990")),
+      ("COUNTRY", answered("country", "Germany")),
+      ("ADDRNUM", answered("address", "1")),
+      ("ADDRLINE1", answered("address[0]", "Street 1")),
+    ];
+    let commands: Vec<&str> = table.iter().map(|(command, _)| *command).collect();
+
+    for ((command, expected), resolved) in table.iter().zip(resolve_all(&FieldMap::builtin(), &commands)) {
+      assert_eq!(&resolved.unwrap(), expected, "{}", command);
+    }
+  }
+
+  #[test]
+  fn rejects_builtin_commands() {
+    // Whether the command is a known item command with an index out of range
+    let table = [
+      ("MAIL3", true),
+      ("MAIL0", true),
+      ("MAIL+1", false),
+      ("MAIL 1", false),
+      ("ADDRLINE-1", false),
+      ("PHONE", false),
+    ];
+
+    for (command, out_of_range) in table {
+      let resolved = resolve_all(&FieldMap::builtin(), &["MAILNUM", "ADDRNUM", command])
+        .pop()
+        .unwrap();
+      let error = resolved.unwrap_err();
+      match error.downcast_ref::<Err>() {
+        Some(Err::InvalidIndex(..)) => assert!(out_of_range, "{}: {}", command, error),
+        Some(Err::UnknownUnexpectedCommand(..)) => assert!(!out_of_range, "{}: {}", command, error),
+        _ => panic!("{}: {}", command, error),
+      }
+    }
+  }
+
+  #[test]
+  fn resolves_rules_from_config() {
+    let config: Config = serde_json::from_str(
+      r#"{"fields": [
+        {"kind": "scalar", "command": "PHONE", "field": "phone"},
+        {"kind": "scalar", "command": "NAME", "field": "name", "format": "uppercase"},
+        {"kind": "scalar", "command": "FAX", "field": "fax", "default": "none"},
+        {"kind": "list", "count": "LINKNUM", "item": "LINK", "field": "links", "format": "lowercase"}
+      ]}"#,
+    )
+    .unwrap();
+    let fields = FieldMap::builtin().with_rules(config.fields().to_vec());
+
+    let table = [
+      ("PHONE", Some(answered("phone", "+49 123"))),
+      ("NAME", Some(answered("name", "JANE DOE"))),
+      ("FAX", Some(answered("fax", "none"))),
+      ("LINKNUM", Some(answered("links", "2"))),
+      ("LINK1", Some(answered("links[0]", "https://example.com/a"))),
+      ("LINK2", Some(answered("links[1]", "https://example.com/b"))),
+      ("LINK+2", None),
+      ("LINKx", None),
+      ("COUNTRY", Some(answered("country", "Germany"))),
+    ];
+    let commands: Vec<&str> = table.iter().map(|(command, _)| *command).collect();
+
+    for ((command, expected), resolved) in table.iter().zip(resolve_all(&fields, &commands)) {
+      match expected {
+        Some(expected) => assert_eq!(&resolved.unwrap(), expected, "{}", command),
+        None => assert!(resolved.is_err(), "{}", command),
+      }
+    }
+  }
+
+  #[test]
+  fn applies_command_policy() {
+    let commands = ["MAIL1", "PHONE", "MAILNUM", "MAIL1"];
+    let count = Some(answered("emails", "2"));
+    let mail = Some(answered("emails[0]", "jane@example.com"));
+
+    // `None` for an error, `Some(None)` for a skipped command
+    for (policy, expected) in [
+      (Policy::Strict, [None, None, count.clone(), mail.clone()]),
+      (Policy::Lenient, [Some(None), Some(None), count.clone(), mail.clone()]),
+      (
+        Policy::AnswerNa,
+        [
+          Some(None),
+          Some(answered("phone", "N/A")),
+          count.clone(),
+          mail.clone(),
+        ],
+      ),
+    ] {
+      let resolved = resolve_all(&FieldMap::builtin().with_policy(policy), &commands);
+
+      for ((command, resolved), expected) in commands.iter().zip(resolved).zip(expected) {
+        assert_eq!(resolved.ok(), expected, "{} {}", policy, command);
+      }
+    }
+  }
+
+  #[test]
+  fn formats_values() {
+    let table = [
+      (Format::Plain, "Jane Doe", Some("Jane Doe")),
+      (Format::Uppercase, "Jane Doe", Some("JANE DOE")),
+      (Format::Lowercase, "Jane Doe", Some("jane doe")),
+      (Format::Date(String::from("%Y-%m-%d")), "01.02.1990", Some("1990-02-01")),
+      (Format::Date(String::from("%d %B %Y")), "01.02.1990", Some("01 February 1990")),
+      (Format::Date(String::from("%Y-%m-%d")), "1990-02-01", None),
+    ];
+
+    for (format, value, expected) in table {
+      let formatted = format.apply(Cow::from(value));
+      assert_eq!(formatted.ok().as_deref(), expected, "{:?} {}", format, value);
+    }
+  }
+}
@@ -0,0 +1,293 @@
+#![warn(clippy::all)]
+
+use crate::cert_info::{CertInfo, Validity};
+use crate::error::Err;
+use crate::tls::{Passphrase, TlsConfig};
+use crate::x509;
+use crate::Res;
+use log::{debug, warn};
+use parking_lot::Mutex;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+/// The encodings a certificate, key or bundle file can be in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+  Pem,
+  Der,
+  Pkcs12,
+}
+
+/// The passphrase once it was resolved, shared by the clones of a TLS configuration so that
+/// retried sessions and the diagnose steps do not ask for it again.
+#[derive(Clone, Default)]
+pub(crate) struct PassphraseCache(Arc<Mutex<Option<String>>>);
+
+impl fmt::Debug for PassphraseCache {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let cached = if self.0.lock().is_some() {
+      "cached"
+    } else {
+      "empty"
+    };
+    write!(f, "PassphraseCache({})", cached)
+  }
+}
+
+/// Resolves the passphrase of encrypted keys and bundles the first time one is needed.
+pub(crate) struct Passphrases<'a> {
+  source: &'a Passphrase,
+  cache: &'a PassphraseCache,
+}
+
+impl<'a> Passphrases<'a> {
+  fn new(config: &'a TlsConfig) -> Self {
+    Self { source: config.passphrase(), cache: config.passphrase_cache() }
+  }
+
+  pub(crate) fn get(&mut self, origin: &str) -> Res<String> {
+    let mut cached = self.cache.0.lock();
+
+    if cached.is_none() {
+      let passphrase = match self.source {
+        Passphrase::Prompt => prompt(&format!("Passphrase for {}: ", origin)).or_else(|e| {
+          debug!("Could not prompt for a passphrase: {}", e);
+          Err::no_passphrase(origin)
+        })?,
+        Passphrase::Env(var) => std::env::var(var).or_else(|_| Err::missing_env(var))?,
+        Passphrase::File(filename) => {
+          fs::read_to_string(filename)?.lines().next().unwrap_or_default().to_string()
+        }
+      };
+
+      *cached = Some(passphrase);
+    }
+
+    Ok(cached.clone().unwrap_or_default())
+  }
+}
+
+/// The terminal and its settings while a passphrase prompt has echo turned off, so that they
+/// can be restored if the process exits in the meantime.
+#[cfg(unix)]
+static PROMPTING: Mutex<Option<(libc::c_int, libc::termios)>> = parking_lot::const_mutex(None);
+
+/// Restore the terminal settings changed by a pending passphrase prompt, e.g. before exiting
+/// from a signal handler while the prompt has echo turned off.
+#[cfg(unix)]
+pub fn restore_terminal() {
+  if let Some((fd, original)) = PROMPTING.lock().take() {
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &original) };
+  }
+}
+
+#[cfg(not(unix))]
+pub fn restore_terminal() {}
+
+/// Read a line from the terminal without echoing it.
+#[cfg(unix)]
+fn prompt(prompt: &str) -> Res<String> {
+  use std::io::{self, BufRead, Write};
+  use std::os::unix::io::AsRawFd;
+
+  let mut tty = fs::OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+  write!(tty, "{}", prompt)?;
+  tty.flush()?;
+
+  let fd = tty.as_raw_fd();
+  let mut original = std::mem::MaybeUninit::<libc::termios>::uninit();
+
+  if unsafe { libc::tcgetattr(fd, original.as_mut_ptr()) } != 0 {
+    return Err(io::Error::last_os_error().into());
+  }
+
+  let original = unsafe { original.assume_init() };
+  let mut silent = original;
+  silent.c_lflag &= !libc::ECHO;
+  silent.c_lflag |= libc::ECHONL;
+
+  *PROMPTING.lock() = Some((fd, original));
+
+  if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &silent) } != 0 {
+    let error = io::Error::last_os_error();
+    PROMPTING.lock().take();
+    return Err(error.into());
+  }
+
+  let mut line = String::new();
+  let res = io::BufReader::new(&tty).read_line(&mut line);
+  restore_terminal();
+  res?;
+
+  Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+#[cfg(not(unix))]
+fn prompt(_prompt: &str) -> Res<String> {
+  Err("Passphrase prompts are only supported on Unix".into())
+}
+
+/// The DER-encoded certificates and private key found in a single file.
+#[derive(Default)]
+pub(crate) struct Material {
+  pub(crate) key: Option<Vec<u8>>,
+  pub(crate) certs: Vec<Vec<u8>>,
+}
+
+impl Material {
+  /// Read a PEM, DER or PKCS#12 file, detecting its format from its contents.
+  fn read(filename: &Path, passphrases: &mut Passphrases) -> Res<Self> {
+    let mut reader = BufReader::new(File::open(filename)?);
+    let mut contents = vec![];
+    reader.read_to_end(&mut contents)?;
+
+    Self::parse(&format!("`{}`", filename.display()), &contents, passphrases)
+  }
+
+  /// Parse the contents of a file or variable named `origin`, detecting its format.
+  fn parse(origin: &str, contents: &[u8], passphrases: &mut Passphrases) -> Res<Self> {
+    let (format, material) = x509::parse(origin, contents, passphrases)?;
+
+    debug!(
+      "Read {} bytes from {} ({:?}): {} certificates, {} private key",
+      contents.len(),
+      origin,
+      format,
+      material.certs.len(),
+      if material.key.is_some() {
+        "with"
+      } else {
+        "without"
+      },
+    );
+
+    Ok(material)
+  }
+}
+
+pub(crate) fn contains(contents: &[u8], needle: &[u8]) -> bool {
+  contents.windows(needle.len()).any(|window| window == needle)
+}
+
+/// The DER-encoded client certificate, its private key and the issuer chain sent along with
+/// it.
+pub(crate) struct Identity {
+  key: Vec<u8>,
+  cert: Vec<u8>,
+  chain: Vec<Vec<u8>>,
+}
+
+impl Identity {
+  /// Load the identity from the certificate file, taking the key and the chain from their
+  /// own files (or environment variable) if given.
+  pub(crate) fn load(config: &TlsConfig) -> Res<Self> {
+    let mut passphrases = Passphrases::new(config);
+    let cert_origin = format!("`{}`", config.cert_file().display());
+    let mut material = Material::read(config.cert_file(), &mut passphrases)?;
+
+    if material.certs.is_empty() {
+      return Err::no_certificate(&cert_origin);
+    }
+
+    let cert = material.certs.remove(0);
+    let mut chain = material.certs;
+
+    if let Some(chain_file) = config.chain_file() {
+      chain.extend(Material::read(chain_file, &mut passphrases)?.certs);
+    }
+
+    let (key_origin, key) = if let Some(var) = config.key_env() {
+      let origin = format!("environment variable `{}`", var);
+      let contents = std::env::var(var).or_else(|_| Err::missing_env(var))?;
+      let key = Material::parse(&origin, contents.as_bytes(), &mut passphrases)?.key;
+      (origin, key)
+    } else if let Some(key_file) = config.key_file() {
+      (format!("`{}`", key_file.display()), Material::read(key_file, &mut passphrases)?.key)
+    } else {
+      (cert_origin, material.key)
+    };
+    let key = match key {
+      Some(key) => key,
+      None => return Err::no_private_key(&key_origin),
+    };
+
+    debug!("Client certificate: {}", CertInfo::new(&cert)?.subject());
+    for issuer in &chain {
+      debug!("  Issued by: {}", CertInfo::new(issuer)?.subject());
+    }
+
+    Ok(Self { key, cert, chain })
+  }
+
+  /// Load the identity to connect with, refusing a key that does not belong to the
+  /// certificate and, unless allowed, a certificate that is not valid now.
+  pub(crate) fn load_checked(config: &TlsConfig) -> Res<Self> {
+    let identity = Self::load(config)?;
+    identity.check_validity(config)?;
+    identity.check_key()?;
+    Ok(identity)
+  }
+
+  /// Refuse to connect with a private key that does not belong to the client certificate.
+  fn check_key(&self) -> Res<()> {
+    if !x509::key_matches(&self.cert, &self.key)? {
+      return Err::key_mismatch(CertInfo::new(&self.cert)?.subject());
+    }
+
+    Ok(())
+  }
+
+  /// Refuse to connect with a client certificate that is not valid now, which the server
+  /// would only reject with an obscure handshake error.
+  fn check_validity(&self, config: &TlsConfig) -> Res<()> {
+    let info = CertInfo::new(&self.cert)?;
+
+    match info.validity() {
+      Validity::Valid(days) => {
+        debug!("Client certificate {} is valid until {} ({} days)", info.subject(), info.not_after(), days);
+        Ok(())
+      }
+      validity if config.allow_expired() => {
+        warn!("Client certificate {}: {}, using it anyway", info.subject(), validity);
+        Ok(())
+      }
+      Validity::Expired(_) => Err::certificate_expired(info.subject(), info.not_after()),
+      Validity::NotYetValid(_) => Err::certificate_not_yet_valid(info.subject(), info.not_before()),
+    }
+  }
+
+  /// Get a reference to the DER-encoded private key.
+  pub(crate) fn key(&self) -> &[u8] {
+    &self.key
+  }
+
+  /// Get a reference to the DER-encoded client certificate.
+  pub(crate) fn cert(&self) -> &[u8] {
+    &self.cert
+  }
+
+  /// Get a reference to the DER-encoded issuer certificates following the client certificate.
+  pub(crate) fn chain(&self) -> &[Vec<u8>] {
+    &self.chain
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn passphrase_file_uses_the_first_line() {
+    let filename = std::env::temp_dir().join(format!("exasol-passphrase-{}.txt", std::process::id()));
+    fs::write(&filename, "secret\r\nnot part of it\n").unwrap();
+
+    let config = TlsConfig::new(Path::new("cert.pem")).with_passphrase(Passphrase::File(filename.clone()));
+    let passphrase = Passphrases::new(&config).get("test");
+    fs::remove_file(&filename).unwrap();
+
+    assert_eq!(passphrase.unwrap(), "secret");
+  }
+}
@@ -2,6 +2,9 @@
 
 use std::io::{self, Read};
 
+/// Read into `data` until the predicate matches a byte. Bytes read before an error are kept
+/// in `data`, so a read interrupted by a timeout can be resumed. Fails with `UnexpectedEof`
+/// if the reader ends before.
 pub(crate) fn read_until<R: Read>(
   reader: &mut R,
   data: &mut Vec<u8>,
@@ -9,13 +12,14 @@ pub(crate) fn read_until<R: Read>(
 ) -> io::Result<()> {
   let mut buffer = [0_u8; 1024];
 
-  data.clear();
-
   loop {
     let bytes = reader.read(&mut buffer)?;
 
     if bytes == 0 {
-      break;
+      return Err(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "Connection closed before the end of the line",
+      ));
     }
 
     for &byte in &buffer[..bytes] {
@@ -26,6 +30,4 @@ pub(crate) fn read_until<R: Read>(
       data.push(byte);
     }
   }
-
-  Ok(())
 }
@@ -0,0 +1,127 @@
+#![warn(clippy::all)]
+
+use crate::endpoint::Endpoint;
+use crate::tls::TlsConfig;
+use crate::Res;
+use chrono::Utc;
+use log::{debug, error};
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Environment variable naming the keylog file when none is configured, as honoured by
+/// browsers and most TLS libraries.
+const SSLKEYLOGFILE: &str = "SSLKEYLOGFILE";
+
+/// The keylog files opened so far, shared by the clones of a TLS configuration. They are
+/// appended to when opened again, e.g. by a retried session, so that the secrets of earlier
+/// attempts are kept.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct KeyLogFiles(Arc<parking_lot::Mutex<HashSet<PathBuf>>>);
+
+impl KeyLogFiles {
+  /// Get whether the keylog file is opened for the first time with this configuration.
+  fn first_open(&self, filename: &Path) -> bool {
+    self.0.lock().insert(filename.to_path_buf())
+  }
+}
+
+/// The keylog file the TLS secrets of a session are written to, in the NSS key log format.
+#[derive(Debug)]
+pub(crate) struct KeyLog {
+  filename: PathBuf,
+  file: Mutex<File>,
+}
+
+impl KeyLog {
+  /// Open the keylog file of a session with the given endpoint, if any. The configured file
+  /// is truncated the first time it is opened with the configuration unless appending is
+  /// enabled, the one from
+  /// `SSLKEYLOGFILE` is always appended to.
+  pub(crate) fn open(config: &TlsConfig, endpoint: &Endpoint) -> Res<Option<Self>> {
+    let (template, append) = match config.keylog_file() {
+      Some(keylog_file) => (keylog_file.to_path_buf(), config.keylog_append()),
+      None => match std::env::var_os(SSLKEYLOGFILE) {
+        Some(keylog_file) if !keylog_file.is_empty() => (PathBuf::from(keylog_file), true),
+        _ => return Ok(None),
+      },
+    };
+
+    let filename = expand(&template, endpoint);
+    let append = append || !config.keylog_files().first_open(&filename);
+    let file = OpenOptions::new()
+      .write(true)
+      .create(true)
+      .append(append)
+      .truncate(!append)
+      .open(&filename)?;
+    debug!("Opened keylog file `{}` (append: {})", filename.display(), append);
+
+    Ok(Some(Self { filename, file: Mutex::new(file) }))
+  }
+
+  /// Write a line to the keylog file, logging any error since the handshake should go on.
+  pub(crate) fn write_line(&self, line: &str) {
+    match self.file.lock() {
+      Ok(mut file) => {
+        if let Err(e) = writeln!(file, "{}", line) {
+          error!("Error writing to keylog file `{}`: {}", self.filename.display(), e);
+        }
+
+        if let Err(e) = file.flush() {
+          error!("Error flushing keylog file `{}`: {}", self.filename.display(), e);
+        }
+      }
+      Err(e) => error!("Error accessing keylog file `{}`: {}", self.filename.display(), e),
+    }
+  }
+}
+
+/// Replace `{timestamp}` and `{endpoint}` in the file name template, e.g. to keep the
+/// secrets of each session in its own file.
+fn expand(template: &Path, endpoint: &Endpoint) -> PathBuf {
+  let template = template.to_string_lossy();
+  let timestamp = Utc::now().format("%Y%m%dT%H%M%S%.3fZ").to_string();
+  let endpoint = format!("{}_{}", endpoint.host(), endpoint.port())
+    .chars()
+    .map(|c| {
+      if c.is_ascii_alphanumeric() || matches!(c, '.' | '-') {
+        c
+      } else {
+        '_'
+      }
+    })
+    .collect::<String>();
+
+  PathBuf::from(template.replace("{timestamp}", &timestamp).replace("{endpoint}", &endpoint))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+
+  #[test]
+  fn truncates_once_then_appends_across_reconnects() {
+    let filename = std::env::temp_dir().join(format!("exasol-keylog-{}.txt", std::process::id()));
+    fs::write(&filename, "EARLIER\n").unwrap();
+
+    let config = TlsConfig::new(Path::new("cert.pem")).with_keylog_file(&filename);
+    let endpoint = Endpoint::new("localhost", 443);
+
+    // A retried session reconnects with a clone of the configuration.
+    for (config, line) in [(config.clone(), "FIRST"), (config, "SECOND")] {
+      KeyLog::open(&config, &endpoint).unwrap().unwrap().write_line(line);
+    }
+    assert_eq!(fs::read_to_string(&filename).unwrap(), "FIRST\nSECOND\n");
+
+    // Another configuration starts over.
+    let config = TlsConfig::new(Path::new("cert.pem")).with_keylog_file(&filename);
+    KeyLog::open(&config, &endpoint).unwrap().unwrap().write_line("THIRD");
+    assert_eq!(fs::read_to_string(&filename).unwrap(), "THIRD\n");
+
+    fs::remove_file(&filename).unwrap();
+  }
+}
@@ -4,12 +4,45 @@ use std::error::Error;
 
 pub type Res<T> = Result<T, Box<dyn Error>>;
 
+#[cfg(not(any(feature = "openssl", feature = "rustls")))]
+compile_error!("Either the `openssl` or the `rustls` feature is needed for TLS");
+
+mod identity;
 mod io;
+mod keylog;
+#[cfg(not(feature = "rustls"))]
+mod openssl_x509;
 mod pow;
+#[cfg(feature = "rustls")]
+mod rustls_tls;
+#[cfg(feature = "rustls")]
+mod rustls_x509;
+#[cfg(any(not(feature = "rustls"), feature = "async"))]
 mod ssl;
 
+#[cfg(not(feature = "rustls"))]
+use openssl_x509 as x509;
+#[cfg(feature = "rustls")]
+use rustls_x509 as x509;
+
+#[cfg(feature = "async")]
+pub mod async_protocol;
+pub mod cancel;
+pub mod cert_info;
+pub mod command;
+pub mod config;
+pub mod diagnose;
+pub mod endpoint;
 pub mod error;
+pub mod fields;
+pub mod observer;
 pub mod protocol;
+pub mod proxy;
+pub mod replay;
+pub mod session;
+pub mod solver;
+pub mod tls;
+pub mod transcript;
 pub mod userdata;
 
 pub use error::Err as ExasolErr;
@@ -1,12 +1,24 @@
 #![warn(clippy::all)]
 
-use exasol::protocol::Exasol;
+use exasol::cancel::CancelToken;
+use exasol::cert_info::{CertInfo, IdentityInfo};
+use exasol::config::Config;
+use exasol::diagnose::{self, Outcome};
+use exasol::endpoint::{self, Endpoint, Endpoints, Order};
+use exasol::fields::{DuplicatePolicy, FieldMap, Policy};
+use exasol::observer::LogObserver;
+use exasol::protocol;
+use exasol::proxy::Proxy;
+use exasol::replay::{self, Recording};
+use exasol::session::{self, RetryPolicy, Session, SessionConfig};
+use exasol::solver::ParallelSolver;
+use exasol::tls::{Passphrase, Pin, TlsConfig, TlsVersion, Verification};
 use exasol::userdata::UserData;
 use exasol::Res;
 use humantime::format_duration as humantime;
 use log::{debug, error, info, trace, warn};
 use std::path::PathBuf;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -14,35 +26,467 @@ use structopt::StructOpt;
 #[structopt(author = "Fred Morcos <fm@fredmorcos.com>")]
 struct Opt {
   /// Verbose output (can be specified multiple times)
-  #[structopt(short, long, parse(from_occurrences))]
+  #[structopt(short, long, parse(from_occurrences), global = true)]
   verbose: u8,
 
-  /// File to import user data used for submisison.
+  /// File to import user data used for submisison (required unless a subcommand is given).
   #[structopt(short, long, name = "DATA-FILE")]
-  data_file: PathBuf,
+  data_file: Option<PathBuf>,
 
-  /// Certificate file.
-  #[structopt(short, long, name = "CERT-FILE")]
-  cert_file: PathBuf,
+  /// Certificate file in PEM, DER or PKCS#12 format, which may also hold the private key
+  /// and the issuer chain (required unless a subcommand is given).
+  #[structopt(short, long, alias = "cert", name = "CERT-FILE", global = true)]
+  cert_file: Option<PathBuf>,
 
-  /// Keylog file (e.g. for use with Wireshark).
-  #[structopt(short, long, name = "KEYLOG-FILE")]
+  /// Private key file in PEM or DER format, if the key is not in the certificate file.
+  #[structopt(long = "key", name = "KEY-FILE", global = true)]
+  key_file: Option<PathBuf>,
+
+  /// Environment variable holding the private key in PEM format, instead of a key file.
+  #[structopt(long, name = "KEY-VAR", conflicts_with = "KEY-FILE", global = true)]
+  key_env: Option<String>,
+
+  /// Environment variable holding the passphrase of an encrypted key or PKCS#12 bundle
+  /// (prompted for otherwise).
+  #[structopt(long, name = "PASSPHRASE-VAR", global = true)]
+  passphrase_env: Option<String>,
+
+  /// File holding the passphrase of an encrypted key or PKCS#12 bundle on its first line
+  /// (prompted for otherwise).
+  #[structopt(long, name = "PASSPHRASE-FILE", conflicts_with = "PASSPHRASE-VAR", global = true)]
+  passphrase_file: Option<PathBuf>,
+
+  /// Issuer chain file in PEM, DER or PKCS#12 format, for issuer certificates that are not in
+  /// the certificate file.
+  #[structopt(long = "chain", name = "CHAIN-FILE", global = true)]
+  chain_file: Option<PathBuf>,
+
+  /// Connect even if the client certificate is expired or not yet valid.
+  #[structopt(long, global = true)]
+  allow_expired: bool,
+
+  /// Keylog file (e.g. for use with Wireshark), instead of the one named by SSLKEYLOGFILE.
+  /// {timestamp} and {endpoint} in the file name are replaced for each session.
+  #[structopt(short, long, name = "KEYLOG-FILE", global = true)]
   keylog_file: Option<PathBuf>,
+
+  /// Append to the keylog file instead of truncating it.
+  #[structopt(long, global = true)]
+  keylog_append: bool,
+
+  /// Transcript file to record the session to (in JSON Lines format).
+  #[structopt(short, long, name = "TRANSCRIPT-FILE")]
+  transcript_file: Option<PathBuf>,
+
+  /// Redact personal data from the transcript.
+  #[structopt(long)]
+  redact: bool,
+
+  /// Go through the session without submitting data to a remote server, printing the
+  /// user data field sent for each command. Only local test servers reached without a proxy
+  /// receive data.
+  #[structopt(long)]
+  dry_run: bool,
+
+  /// Configuration file (command-line options take precedence).
+  #[structopt(long, name = "CONFIG-FILE", global = true)]
+  config_file: Option<PathBuf>,
+
+  /// Server to connect to as HOST:PORT or [IPV6]:PORT (can be specified multiple times,
+  /// defaults to all documented ports of the challenge server).
+  #[structopt(long = "server", name = "HOST:PORT", number_of_values = 1, global = true)]
+  servers: Vec<Endpoint>,
+
+  /// Try the servers in random order instead of the given order.
+  #[structopt(long, global = true)]
+  random_order: bool,
+
+  /// Try the servers in the given order, even if the configuration file enables random order.
+  #[structopt(long, overrides_with = "random-order", global = true)]
+  no_random_order: bool,
+
+  /// Timeout for each connection attempt (e.g. 5s, 1m) [default: 10s].
+  #[structopt(long, name = "TIMEOUT", parse(try_from_str = humantime::parse_duration), global = true)]
+  connect_timeout: Option<Duration>,
+
+  /// Proxy to connect through, as socks5://[USER:PASS@]HOST[:PORT] or
+  /// http://[USER:PASS@]HOST[:PORT] (HTTP CONNECT).
+  #[structopt(long, name = "PROXY", global = true)]
+  proxy: Option<Proxy>,
+
+  /// Total number of session attempts: sessions failing with a transient error (e.g. a
+  /// dropped connection) are run again, but never once the data has been submitted
+  /// [default: 1].
+  #[structopt(long, name = "ATTEMPTS")]
+  attempts: Option<u32>,
+
+  /// Delay before the first retry, doubled for every further retry (e.g. 500ms, 2s)
+  /// [default: 1s].
+  #[structopt(long, name = "BACKOFF", parse(try_from_str = humantime::parse_duration))]
+  retry_backoff: Option<Duration>,
+
+  /// Upper bound of the delay between retries [default: 1m].
+  #[structopt(long, name = "MAX-BACKOFF", parse(try_from_str = humantime::parse_duration))]
+  retry_max_backoff: Option<Duration>,
+
+  /// Time the server may stay silent while a line is awaited (e.g. during the proof-of-work
+  /// or data phase), or take for the TLS handshake, before the session attempt fails with a
+  /// retryable timeout [default: 1m].
+  #[structopt(long, name = "READ-TIMEOUT", parse(try_from_str = humantime::parse_duration))]
+  read_timeout: Option<Duration>,
+
+  /// How to handle unknown data commands and item commands (e.g. MAIL1) sent before their
+  /// count command: strict (abort), lenient (skip both) or answer-na (answer unknown commands
+  /// with N/A and skip early item commands) [default: strict].
+  #[structopt(long, name = "POLICY")]
+  command_policy: Option<Policy>,
+
+  /// How to handle data commands the server sends more than once: warn (answer again) or
+  /// reject (abort) [default: warn].
+  #[structopt(long, name = "DUPLICATE-POLICY")]
+  duplicate_policy: Option<DuplicatePolicy>,
+
+  /// CA bundle (PEM) to verify the server certificate with, instead of the issuer
+  /// certificates in the certificate file.
+  #[structopt(long, name = "CA-FILE", global = true)]
+  ca_file: Option<PathBuf>,
+
+  /// SHA-256 fingerprint the server certificate must match, as spki:HEX (public key) or
+  /// cert:HEX (whole certificate), instead of verifying its issuer.
+  #[structopt(long, name = "PIN", conflicts_with = "CA-FILE", global = true)]
+  pin: Option<Pin>,
+
+  /// Accept any server certificate (for testing only).
+  #[structopt(long, conflicts_with_all = &["CA-FILE", "PIN"], global = true)]
+  insecure: bool,
+
+  /// Verify the server certificate, even if the configuration file sets `insecure`.
+  #[structopt(long, overrides_with = "insecure", global = true)]
+  no_insecure: bool,
+
+  /// Lowest TLS version to negotiate: 1.0, 1.1, 1.2 or 1.3.
+  #[structopt(long, name = "MIN-VERSION", global = true)]
+  tls_min_version: Option<TlsVersion>,
+
+  /// Highest TLS version to negotiate: 1.0, 1.1, 1.2 or 1.3.
+  #[structopt(long, name = "MAX-VERSION", global = true)]
+  tls_max_version: Option<TlsVersion>,
+
+  /// Colon-separated cipher suites to offer for TLS 1.2 and older, in OpenSSL syntax (IANA
+  /// names with rustls).
+  #[structopt(long, name = "CIPHER-LIST", global = true)]
+  cipher_list: Option<String>,
+
+  /// Colon-separated TLS 1.3 cipher suites to offer (e.g. TLS_AES_256_GCM_SHA384).
+  #[structopt(long, name = "CIPHERSUITES", global = true)]
+  ciphersuites: Option<String>,
+
+  /// Colon-separated key exchange groups to offer (e.g. X25519:P-256).
+  #[structopt(long, name = "GROUPS", global = true)]
+  groups: Option<String>,
+
+  /// Server name to send (SNI) and to verify the server certificate against [default: the
+  /// host of the server address if it is a DNS name].
+  #[structopt(long, name = "SERVER-NAME", global = true)]
+  server_name: Option<String>,
+
+  #[structopt(subcommand)]
+  command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+  /// Replay a recorded transcript against the client and check its responses.
+  Replay {
+    /// File to import the user data used in the recorded session.
+    #[structopt(short, long, name = "DATA-FILE")]
+    data_file: PathBuf,
+
+    /// Transcript file recorded with --transcript-file.
+    #[structopt(name = "TRANSCRIPT-FILE")]
+    transcript_file: PathBuf,
+
+    /// Search for a new proof-of-work suffix instead of sending the recorded one again.
+    #[structopt(long)]
+    solve: bool,
+  },
+
+  /// Show the client certificate, its key and its issuer chain, and check that they can be
+  /// used for a session.
+  CertInfo,
+
+  /// Check the connection to the server step by step (DNS, TCP on every port, TLS handshake
+  /// and HELO) and explain the failing steps.
+  Diagnose,
+}
+
+fn run(opt: &Opt, cancel: &CancelToken) -> Res<()> {
+  let config = match &opt.config_file {
+    Some(config_file) => Config::new(config_file)?,
+    None => Config::default(),
+  };
+
+  match &opt.command {
+    Some(Command::Replay { data_file, transcript_file, solve }) => {
+      let userdata = UserData::new(data_file)?;
+      let recording = Recording::new(transcript_file)?;
+      let fields = field_map(opt, &config);
+
+      if *solve {
+        replay::replay(&recording, &userdata, &fields, ParallelSolver)
+      } else {
+        replay::replay(&recording, &userdata, &fields, recording.solver())
+      }
+    }
+    Some(Command::CertInfo) => cert_info(&tls_config(opt, &config)?),
+    Some(Command::Diagnose) => diagnose(&tls_config(opt, &config)?, &endpoints(opt, &config), cancel),
+    None => run_session(opt, &config, cancel),
+  }
+}
+
+fn tls_config(opt: &Opt, config: &Config) -> Res<TlsConfig> {
+  let cert_file = opt.cert_file.as_deref().ok_or("The --cert-file option is required")?;
+
+  let mut tls = TlsConfig::new(cert_file)
+    .with_verification(verification(opt, config))
+    .with_allow_expired(opt.allow_expired);
+
+  if let Some(key_file) = &opt.key_file {
+    tls = tls.with_key_file(key_file);
+  }
+
+  if let Some(key_env) = &opt.key_env {
+    tls = tls.with_key_env(key_env);
+  }
+
+  if let Some(var) = &opt.passphrase_env {
+    tls = tls.with_passphrase(Passphrase::Env(var.clone()));
+  } else if let Some(passphrase_file) = &opt.passphrase_file {
+    tls = tls.with_passphrase(Passphrase::File(passphrase_file.clone()));
+  }
+
+  if let Some(chain_file) = &opt.chain_file {
+    tls = tls.with_chain_file(chain_file);
+  }
+
+  if let Some(keylog_file) = &opt.keylog_file {
+    tls = tls.with_keylog_file(keylog_file).with_keylog_append(opt.keylog_append);
+  }
+
+  if let Some(version) = opt.tls_min_version.or_else(|| config.tls_min_version()) {
+    tls = tls.with_min_version(version);
+  }
+
+  if let Some(version) = opt.tls_max_version.or_else(|| config.tls_max_version()) {
+    tls = tls.with_max_version(version);
+  }
+
+  if let Some(cipher_list) = opt.cipher_list.as_deref().or_else(|| config.cipher_list()) {
+    tls = tls.with_cipher_list(cipher_list);
+  }
+
+  if let Some(ciphersuites) = opt.ciphersuites.as_deref().or_else(|| config.ciphersuites()) {
+    tls = tls.with_ciphersuites(ciphersuites);
+  }
+
+  if let Some(groups) = opt.groups.as_deref().or_else(|| config.groups()) {
+    tls = tls.with_groups(groups);
+  }
+
+  if let Some(server_name) = opt.server_name.as_deref().or_else(|| config.server_name()) {
+    tls = tls.with_server_name(server_name);
+  }
+
+  Ok(tls)
+}
+
+fn cert_info(tls: &TlsConfig) -> Res<()> {
+  let info = IdentityInfo::load(tls)?;
+  let print_cert = |title: &str, cert: &CertInfo| {
+    println!("{}:", title);
+    println!("  Subject:    {}", cert.subject());
+    println!("  Issuer:     {}", cert.issuer());
+    println!("  Not before: {}", cert.not_before());
+    println!("  Not after:  {}", cert.not_after());
+    println!("  Status:     {}", cert.validity());
+  };
+
+  print_cert("Certificate", info.cert());
+  println!("  Key:        {}", info.key_type());
+  println!(
+    "  Key match:  {}",
+    if info.key_matches() {
+      "yes"
+    } else {
+      "NO, the key does not belong to the certificate"
+    }
+  );
+
+  for issuer in info.chain() {
+    print_cert("Issuer certificate", issuer);
+  }
+
+  if info.chain().is_empty() {
+    println!("No issuer certificates");
+  }
+
+  Ok(())
 }
 
-fn run(opt: &Opt) -> Res<()> {
-  const ADDRESS: &str = "18.202.148.130:3336";
-  let userdata = UserData::new(&opt.data_file)?;
+fn diagnose(tls: &TlsConfig, endpoints: &Endpoints, cancel: &CancelToken) -> Res<()> {
+  let passed = diagnose::diagnose(tls, endpoints, cancel, |step| {
+    let elapsed = humantime(Duration::from_millis(step.elapsed().as_millis() as u64));
+    match step.outcome() {
+      Outcome::Passed => println!("[ OK ] {} ({})", step.name(), elapsed),
+      Outcome::Failed { error, explanation } => {
+        println!("[FAIL] {} ({})", step.name(), elapsed);
+        println!("       {}", error);
+        println!("       {}", explanation);
+      }
+      Outcome::Skipped(reason) => println!("[SKIP] {}: {}", step.name(), reason),
+    }
 
-  let _protocol = Exasol::new(&opt.cert_file, opt.keylog_file.as_deref(), ADDRESS)?
-    .connect()?
-    .handshake()?
-    .pow()?
-    .submit(&userdata)?;
+    for detail in step.details() {
+      println!("       {}", detail);
+    }
+  })?;
+
+  if !passed {
+    return Err("Some checks failed".into());
+  }
 
   Ok(())
 }
 
+fn endpoints(opt: &Opt, config: &Config) -> Endpoints {
+  let servers = if !opt.servers.is_empty() {
+    opt.servers.clone()
+  } else if !config.servers().is_empty() {
+    config.servers().to_vec()
+  } else {
+    endpoint::default_endpoints()
+  };
+  let order = if opt.random_order || (config.random_order() && !opt.no_random_order) {
+    Order::Random
+  } else {
+    Order::Sequential
+  };
+  let connect_timeout = opt
+    .connect_timeout
+    .or_else(|| config.connect_timeout())
+    .unwrap_or(endpoint::DEFAULT_CONNECT_TIMEOUT);
+  let endpoints = Endpoints::new(servers, order, connect_timeout);
+
+  match opt.proxy.as_ref().or_else(|| config.proxy()) {
+    Some(proxy) => endpoints.with_proxy(proxy.clone()),
+    None => endpoints,
+  }
+}
+
+fn field_map(opt: &Opt, config: &Config) -> FieldMap {
+  let policy = opt.command_policy.or_else(|| config.command_policy()).unwrap_or_default();
+  let duplicates = opt.duplicate_policy.or_else(|| config.duplicate_policy()).unwrap_or_default();
+
+  FieldMap::builtin()
+    .with_rules(config.fields().to_vec())
+    .with_policy(policy)
+    .with_duplicates(duplicates)
+}
+
+fn verification(opt: &Opt, config: &Config) -> Verification {
+  if opt.insecure {
+    Verification::Insecure
+  } else if let Some(pin) = &opt.pin {
+    Verification::Pin(pin.clone())
+  } else if let Some(ca_file) = &opt.ca_file {
+    Verification::CaFile(ca_file.clone())
+  } else if config.insecure() && !opt.no_insecure {
+    Verification::Insecure
+  } else if let Some(pin) = config.pin() {
+    Verification::Pin(pin.clone())
+  } else if let Some(ca_file) = config.ca_file() {
+    Verification::CaFile(ca_file.to_path_buf())
+  } else {
+    Verification::Chain
+  }
+}
+
+fn run_session(opt: &Opt, config: &Config, cancel: &CancelToken) -> Res<()> {
+  let data_file = opt.data_file.as_deref().ok_or("The --data-file option is required")?;
+  let tls = tls_config(opt, config)?;
+
+  let userdata = UserData::new(data_file)?;
+
+  let endpoints = endpoints(opt, config);
+  // Behind a proxy the server's address is resolved by the proxy, so it may be loopback
+  // there while being remote here.
+  let via_proxy = endpoints.proxy().is_some();
+
+  let retry = RetryPolicy::new(
+    opt.attempts.or_else(|| config.attempts()).unwrap_or(1),
+    opt
+      .retry_backoff
+      .or_else(|| config.retry_backoff())
+      .unwrap_or(session::DEFAULT_INITIAL_BACKOFF),
+    opt
+      .retry_max_backoff
+      .or_else(|| config.retry_max_backoff())
+      .unwrap_or(session::DEFAULT_MAX_BACKOFF),
+  );
+
+  let read_timeout = opt
+    .read_timeout
+    .or_else(|| config.read_timeout())
+    .unwrap_or(protocol::DEFAULT_READ_TIMEOUT);
+
+  let mut session_config = SessionConfig::new(tls, endpoints)
+    .with_fields(field_map(opt, config))
+    .with_retry(retry)
+    .with_read_timeout(read_timeout)
+    .with_cancel(cancel.clone());
+
+  if let Some(transcript_file) = &opt.transcript_file {
+    session_config = session_config.with_transcript_file(transcript_file, opt.redact);
+  }
+
+  if opt.dry_run {
+    let protocol = Session::connect(&session_config, ParallelSolver, LogObserver)?
+      .handshake()?
+      .pow()?;
+    let send = !via_proxy && protocol.endpoint().is_loopback();
+
+    if !send {
+      println!("Dry run against {}: stopping before any data is sent", protocol.endpoint());
+    }
+
+    protocol.dry_run(&userdata, send, |field| {
+      println!("{} -> {} = `{}` (hash {})", field.command(), field.name(), field.datum(), field.hash());
+    })?;
+
+    return Ok(());
+  }
+
+  let report = Session::run(&session_config, &userdata, ParallelSolver, LogObserver)?;
+  let timings = report.timings();
+
+  info!("Session completed through {}", report.endpoint());
+  info!(
+    "  Connect {}, handshake {}, POW {}, data {}",
+    humantime(timings.connect()),
+    humantime(timings.handshake()),
+    humantime(timings.pow()),
+    humantime(timings.data())
+  );
+
+  Ok(())
+}
+
+/// Exit status when the session, replay or diagnosis failed.
+const EXIT_FAILURE: i32 = 1;
+
+/// Exit status after SIGINT or SIGTERM, as a shell reports a process killed by SIGINT.
+const EXIT_INTERRUPTED: i32 = 130;
+
 fn main() {
   let start_time = Instant::now();
   let opt = Opt::from_args();
@@ -70,12 +514,27 @@ fn main() {
   debug!("Debug output enabled.");
   trace!("Trace output enabled.");
 
-  if let Err(e) = run(&opt) {
-    if have_logger {
-      error!("Error: {}", e);
-    } else {
-      eprintln!("Error: {}", e);
+  let cancel = CancelToken::new();
+  let handler_cancel = cancel.clone();
+  let handler = ctrlc::set_handler(move || {
+    if handler_cancel.is_cancelled() {
+      eprintln!("Interrupted again, exiting immediately");
+      exasol::tls::restore_terminal();
+      std::process::exit(EXIT_INTERRUPTED);
     }
+
+    eprintln!("Interrupted, closing the session (interrupt again to exit immediately)");
+    handler_cancel.cancel();
+  });
+
+  if let Err(e) = handler {
+    warn!("Could not install the signal handler: {}", e);
+  }
+
+  // Printed whatever the log level, as the exit status alone does not tell what failed.
+  let res = run(&opt, &cancel);
+  if let Err(e) = &res {
+    eprintln!("Error: {}", e);
   }
 
   if have_logger {
@@ -83,4 +542,12 @@ fn main() {
   } else {
     eprintln!("Total time: {}", humantime(Instant::now().duration_since(start_time)));
   }
+
+  if cancel.is_cancelled() {
+    std::process::exit(EXIT_INTERRUPTED);
+  }
+
+  if res.is_err() {
+    std::process::exit(EXIT_FAILURE);
+  }
 }
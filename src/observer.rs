@@ -0,0 +1,113 @@
+#![warn(clippy::all)]
+
+use crate::endpoint::Endpoint;
+use crate::fields::Report;
+use crate::protocol::Field;
+use humantime::format_duration as humantime;
+use log::{debug, info, trace, warn};
+use std::error::Error;
+use std::time::Duration;
+
+/// Callbacks for the events of a protocol session. All methods do nothing by default, so
+/// implementations only need to override the events they are interested in.
+pub trait SessionObserver: Send {
+  /// The TLS connection to the server was established.
+  fn connected(&mut self, _endpoint: &Endpoint) {}
+
+  /// A line was received from the server, without the trailing newline.
+  fn line_received(&mut self, _line: &str) {}
+
+  /// A line was sent to the server, without the trailing newline.
+  fn line_sent(&mut self, _line: &str) {}
+
+  /// The session moved from one state to another.
+  fn state_changed(&mut self, _from: &'static str, _to: &'static str) {}
+
+  /// The proof-of-work search started.
+  fn pow_started(&mut self, _authdata: &str, _difficulty: usize) {}
+
+  /// The proof-of-work search is still running.
+  fn pow_progress(&mut self, _iterations: usize, _elapsed: Duration) {}
+
+  /// The proof-of-work search found a suffix.
+  fn pow_solved(&mut self, _suffix: &str, _digest: &str) {}
+
+  /// A user data field was sent to the server.
+  fn field_submitted(&mut self, _field: &Field) {}
+
+  /// The server sent END and the data phase was checked for completeness.
+  fn report(&mut self, _report: &Report) {}
+
+  /// The session failed.
+  fn error(&mut self, _error: &dyn Error) {}
+
+  /// The client's OK acknowledging the server's END was flushed to the server, before it is
+  /// recorded or the data phase is checked.
+  fn end_acknowledged(&mut self) {}
+
+  /// The server sent END and the client acknowledged it.
+  fn end(&mut self) {}
+}
+
+/// An observer that ignores all events.
+impl SessionObserver for () {}
+
+/// An observer that reports session events through the `log` crate.
+#[derive(Default)]
+pub struct LogObserver;
+
+impl SessionObserver for LogObserver {
+  fn connected(&mut self, endpoint: &Endpoint) {
+    info!("Session connected to {}", endpoint);
+  }
+
+  fn line_received(&mut self, line: &str) {
+    trace!("Received `{}`", line);
+  }
+
+  fn line_sent(&mut self, line: &str) {
+    trace!("Sent `{}`", line);
+  }
+
+  fn state_changed(&mut self, from: &'static str, to: &'static str) {
+    debug!("State {} -> {}", from, to);
+  }
+
+  fn pow_started(&mut self, authdata: &str, difficulty: usize) {
+    debug!("Authdata = {}  |  Difficulty = {}", authdata, difficulty);
+  }
+
+  fn pow_progress(&mut self, iterations: usize, elapsed: Duration) {
+    info!("POW running for {}: {} iterations", humantime(elapsed), iterations);
+  }
+
+  fn pow_solved(&mut self, suffix: &str, digest: &str) {
+    info!("POW solved: suffix `{}`, digest {}", suffix, digest);
+  }
+
+  fn field_submitted(&mut self, field: &Field) {
+    debug!("  Submitted {} for {}: `{}`", field.name(), field.command(), field.datum());
+  }
+
+  fn report(&mut self, report: &Report) {
+    if !report.missing().is_empty() {
+      warn!("Server never requested: {}", report.missing().join(", "));
+    }
+
+    if !report.duplicates().is_empty() {
+      warn!("Server requested more than once: {}", report.duplicates().join(", "));
+    }
+
+    if report.is_complete() {
+      info!("Server requested all {} fields", report.answered().len());
+    }
+  }
+
+  fn error(&mut self, error: &dyn Error) {
+    debug!("Session failed: {}", error);
+  }
+
+  fn end(&mut self) {
+    info!("Successfully submitted data to server");
+  }
+}
@@ -0,0 +1,136 @@
+#![warn(clippy::all)]
+
+use crate::cert_info::CertFields;
+use crate::error::Err;
+use crate::identity::{contains, Format, Material, Passphrases};
+use crate::Res;
+use chrono::{DateTime, TimeZone, Utc};
+use openssl::asn1::{Asn1Time, Asn1TimeRef};
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::{Id, PKey, PKeyRef, Private};
+use openssl::x509::{X509NameRef, X509};
+
+/// Parse the PEM, DER or PKCS#12 contents of a file or variable named `origin`.
+pub(crate) fn parse(origin: &str, contents: &[u8], passphrases: &mut Passphrases) -> Res<(Format, Material)> {
+  if contains(contents, b"-----BEGIN ") {
+    let certs = X509::stack_from_pem(contents)?;
+    let key = if !contains(contents, b"PRIVATE KEY-----") {
+      None
+    } else if contains(contents, b"ENCRYPTED") {
+      let passphrase = passphrases.get(origin)?;
+      match PKey::private_key_from_pem_passphrase(contents, passphrase.as_bytes()) {
+        Ok(key) => Some(key),
+        Err(_) => return Err::wrong_passphrase(origin),
+      }
+    } else {
+      Some(PKey::private_key_from_pem(contents)?)
+    };
+
+    let key = key.as_deref().map(key_to_der).transpose()?;
+    return Ok((Format::Pem, Material { key, certs: certs_to_der(&certs)? }));
+  }
+
+  if let Ok(cert) = X509::from_der(contents) {
+    return Ok((Format::Der, Material { key: None, certs: vec![cert.to_der()?] }));
+  }
+
+  if let Ok(key) = PKey::private_key_from_der(contents) {
+    return Ok((Format::Der, Material { key: Some(key_to_der(&key)?), certs: vec![] }));
+  }
+
+  let pkcs12 = match Pkcs12::from_der(contents) {
+    Ok(pkcs12) => pkcs12,
+    Err(_) => return Err::unknown_format(origin),
+  };
+  let pkcs12 = match pkcs12.parse2("") {
+    Ok(parsed) => parsed,
+    Err(_) => match pkcs12.parse2(&passphrases.get(origin)?) {
+      Ok(parsed) => parsed,
+      Err(_) => return Err::wrong_passphrase(origin),
+    },
+  };
+  let certs: Vec<X509> = pkcs12.cert.into_iter().chain(pkcs12.ca.into_iter().flatten()).collect();
+  let key = pkcs12.pkey.as_deref().map(key_to_der).transpose()?;
+
+  Ok((Format::Pkcs12, Material { key, certs: certs_to_der(&certs)? }))
+}
+
+fn key_to_der(key: &PKeyRef<Private>) -> Res<Vec<u8>> {
+  Ok(key.private_key_to_pkcs8()?)
+}
+
+fn certs_to_der(certs: &[X509]) -> Res<Vec<Vec<u8>>> {
+  certs.iter().map(|cert| Ok(cert.to_der()?)).collect()
+}
+
+/// Get the names and validity period of a DER-encoded certificate.
+pub(crate) fn cert_fields(cert: &[u8]) -> Res<CertFields> {
+  let cert = X509::from_der(cert)?;
+
+  Ok(CertFields {
+    subject: name(cert.subject_name()),
+    issuer: name(cert.issuer_name()),
+    not_before: time(cert.not_before())?,
+    not_after: time(cert.not_after())?,
+  })
+}
+
+/// Format a distinguished name as `CN=...,O=...`.
+fn name(name: &X509NameRef) -> String {
+  name
+    .entries()
+    .map(|entry| {
+      let key = entry.object().nid().short_name().unwrap_or("?");
+      match entry.data().to_string() {
+        Ok(value) => format!("{}={}", key, value),
+        Err(_) => format!("{}=?", key),
+      }
+    })
+    .collect::<Vec<_>>()
+    .join(",")
+}
+
+fn time(time: &Asn1TimeRef) -> Res<DateTime<Utc>> {
+  let diff = Asn1Time::from_unix(0)?.diff(time)?;
+  let secs = i64::from(diff.days) * 86400 + i64::from(diff.secs);
+  Utc
+    .timestamp_opt(secs, 0)
+    .single()
+    .ok_or_else(|| format!("Invalid certificate time {}", time).into())
+}
+
+/// Describe a DER-encoded private key, e.g. `EC prime256v1 (256 bits)`.
+pub(crate) fn key_type(key: &[u8]) -> Res<String> {
+  let key = PKey::private_key_from_der(key)?;
+
+  Ok(match key.id() {
+    Id::EC => {
+      let curve = key
+        .ec_key()
+        .ok()
+        .and_then(|key| key.group().curve_name())
+        .and_then(|nid| nid.short_name().ok());
+      format!("EC {} ({} bits)", curve.unwrap_or("unknown curve"), key.bits())
+    }
+    Id::RSA => format!("RSA ({} bits)", key.bits()),
+    Id::ED25519 => "Ed25519".to_string(),
+    Id::ED448 => "Ed448".to_string(),
+    id => format!("{:?} ({} bits)", id, key.bits()),
+  })
+}
+
+/// Get whether a DER-encoded private key belongs to a DER-encoded certificate.
+pub(crate) fn key_matches(cert: &[u8], key: &[u8]) -> Res<bool> {
+  let key = PKey::private_key_from_der(key)?;
+  Ok(X509::from_der(cert)?.public_key()?.public_eq(&key))
+}
+
+/// Get the DER-encoded SubjectPublicKeyInfo of a DER-encoded certificate.
+pub(crate) fn public_key(cert: &[u8]) -> Res<Vec<u8>> {
+  Ok(X509::from_der(cert)?.public_key()?.public_key_to_der()?)
+}
+
+/// Get the SHA-256 digest of some data.
+pub(crate) fn sha256(data: &[u8]) -> [u8; 32] {
+  openssl::sha::sha256(data)
+}
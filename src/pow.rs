@@ -12,10 +12,7 @@ use sha1::digest::FixedOutput;
 use sha1::{Digest, Sha1};
 use std::sync::atomic::{AtomicBool, AtomicUsize};
 use std::sync::Arc;
-use std::time::Instant;
-
-#[cfg(feature = "stats")]
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 type Distribution = rand::distributions::Uniform<u8>;
 type BytesRng = DistIter<Distribution, Xoshiro128PlusPlus, u8>;
@@ -130,8 +127,33 @@ fn get_matches_difficulty_pred(difficulty: usize) -> fn(&[u8]) -> bool {
   DIFF_FUNC_TABLE[difficulty]
 }
 
-pub(crate) fn pow(authdata: &str, difficulty: usize) -> Res<Option<Vec<u8>>> {
+/// Check that a suffix is a valid solution for the given authdata and difficulty.
+pub(crate) fn verify(authdata: &str, suffix: &[u8], difficulty: usize) -> bool {
+  const FORBIDDEN: &[u8] = b"\t\n\r ";
+
+  if difficulty > 9 || suffix.is_empty() || suffix.iter().any(|b| FORBIDDEN.contains(b)) {
+    return false;
+  }
+
+  let mut base_hasher = Sha1::default();
+  base_hasher.update(authdata.as_bytes());
+
+  let mut hashed = Default::default();
+  hash(base_hasher, suffix, &mut hashed);
+  get_matches_difficulty_pred(difficulty)(&hashed)
+}
+
+/// Search for a suffix matching the difficulty. Returns `None` if the search is cancelled by
+/// setting `cancel`. The total number of iterations so far and the elapsed time are
+/// periodically passed to `progress`.
+pub(crate) fn pow(
+  authdata: &str,
+  difficulty: usize,
+  cancel: &AtomicBool,
+  progress: &(dyn Fn(usize, Duration) + Sync),
+) -> Res<Option<Vec<u8>>> {
   const LEN: usize = 8;
+  const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
 
   let matches_difficulty_pred = get_matches_difficulty_pred(difficulty);
 
@@ -163,6 +185,7 @@ pub(crate) fn pow(authdata: &str, difficulty: usize) -> Res<Option<Vec<u8>>> {
         let (mut time_gen, mut time_hashing, mut time_matching, mut time_stop) = setup_duration_counters();
 
         let mut iterations = 0;
+        let mut last_progress = Instant::now();
         loop {
           iterations += 1;
 
@@ -205,7 +228,22 @@ pub(crate) fn pow(authdata: &str, difficulty: usize) -> Res<Option<Vec<u8>>> {
           }
 
           let do_stop = iterations % 10_000 == 0;
-          let do_stop = do_stop && timeit!(stop.load(std::sync::atomic::Ordering::Acquire) => time_stop);
+
+          if do_stop {
+            total_iters.fetch_add(10_000, std::sync::atomic::Ordering::Release);
+
+            // Only the first thread reports progress.
+            if thread_idx == 0 && last_progress.elapsed() >= PROGRESS_INTERVAL {
+              last_progress = Instant::now();
+              progress(total_iters.load(std::sync::atomic::Ordering::Acquire), start.elapsed());
+            }
+          }
+
+          let do_stop = do_stop
+            && timeit!(
+              stop.load(std::sync::atomic::Ordering::Acquire)
+                || cancel.load(std::sync::atomic::Ordering::Acquire) => time_stop
+            );
           if do_stop {
             debug!("Thread {}: Stopping", thread_idx);
             break;
@@ -229,7 +267,7 @@ pub(crate) fn pow(authdata: &str, difficulty: usize) -> Res<Option<Vec<u8>>> {
           humantime(time_stop),
         );
 
-        total_iters.fetch_add(iterations, std::sync::atomic::Ordering::Release);
+        total_iters.fetch_add(iterations % 10_000, std::sync::atomic::Ordering::Release);
       })
     }
   });
@@ -1,62 +1,94 @@
 #![warn(clippy::all)]
 
-use crate::pow::pow;
-use crate::ssl::create_ssl_stream;
+use crate::cancel::CancelToken;
+use crate::command::ServerCommand;
+use crate::endpoint::{Endpoint, Endpoints};
+use crate::fields::{DuplicatePolicy, FieldMap, Policy, Report};
+use crate::observer::SessionObserver;
+use crate::solver::{ParallelSolver, Solver};
+use crate::tls::{is_interruption, TlsConfig, TlsSession, TlsStream};
+use crate::transcript::{Event, Transcript, REDACTED};
 use crate::Res;
 use crate::{error::Err, userdata::UserData};
-use log::{debug, info};
-use openssl::ssl::SslStream;
+use log::{debug, info, warn};
 use sha1::{Digest, Sha1};
-use std::{io::Write, net::TcpStream, path::Path, str::Split};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+/// Default time the server may stay silent while the client waits for a line, before the
+/// session fails with a timeout.
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub trait State: Default {
+  /// Name of the state as used in logs and transcripts.
+  const NAME: &'static str;
+}
 
-pub trait State: Default {}
-impl State for () {}
+impl State for () {
+  const NAME: &'static str = "INIT";
+}
 
 #[derive(Default)]
 pub struct StateHelo;
-impl State for StateHelo {}
+impl State for StateHelo {
+  const NAME: &'static str = "HELO";
+}
 
 #[derive(Default)]
 pub struct StatePow;
-impl State for StatePow {}
+impl State for StatePow {
+  const NAME: &'static str = "POW";
+}
 
 #[derive(Default)]
-pub struct StateEnd;
-impl State for StateEnd {}
+pub struct StateEnd {
+  report: Report,
+}
+impl State for StateEnd {
+  const NAME: &'static str = "END";
+}
+
+impl StateEnd {
+  pub(crate) fn new(report: Report) -> Self {
+    Self { report }
+  }
+
+  /// Get a reference to the completeness report of the data phase.
+  pub fn report(&self) -> &Report {
+    &self.report
+  }
+}
 
 #[derive(Default)]
 pub struct StateData {
-  got_mailnum: bool,
-  got_addrnum: bool,
+  counted: HashSet<String>,
+  requested: Vec<String>,
+  answered: Vec<String>,
+  authdata: String,
   hasher: Sha1,
 }
-impl State for StateData {}
+impl State for StateData {
+  const NAME: &'static str = "DATA";
+}
 
 impl StateData {
   pub fn new(authdata: &str) -> Self {
     let mut hasher = Sha1::new();
     hasher.update(authdata);
-    Self { got_mailnum: false, got_addrnum: false, hasher }
-  }
-
-  /// Get state's mailnum status.
-  pub fn got_mailnum(&self) -> bool {
-    self.got_mailnum
+    Self { authdata: authdata.to_string(), hasher, ..Default::default() }
   }
 
-  /// Set the state's mailnum status to true.
-  pub fn set_got_mailnum(&mut self) {
-    self.got_mailnum = true;
+  /// Get a reference to the authdata sent by the server with the POW command.
+  pub fn authdata(&self) -> &str {
+    &self.authdata
   }
 
-  /// Get the state's addrnum status.
-  pub fn got_addrnum(&self) -> bool {
-    self.got_addrnum
-  }
-
-  /// Set the state's addrnum status to true.
-  pub fn set_got_addrnum(&mut self) {
-    self.got_addrnum = true;
+  /// Get whether the server already requested the length of a list field (e.g. `emails`
+  /// with MAILNUM).
+  pub fn got_count(&self, field: &str) -> bool {
+    self.counted.contains(field)
   }
 
   pub fn hash(&self, data: &str) -> [u8; 20] {
@@ -64,199 +96,765 @@ impl StateData {
     hasher.update(data);
     hasher.finalize().into()
   }
+
+  /// Find the user data field a data command asks for, or `None` if the field mapping
+  /// policy skips the command.
+  pub(crate) fn field<'u>(
+    &mut self,
+    command: &ServerCommand,
+    fields: &FieldMap,
+    userdata: &'u UserData,
+  ) -> Res<Option<Field<'u>>> {
+    let hash = match command.arg() {
+      Some(arg) => self.hash(arg),
+      None if fields.policy() == Policy::Strict => {
+        return Err::unknown_or_unexpected_command(&command.name(), "a data command")
+      }
+      None => {
+        warn!("Skipping unexpected command {}", command.name());
+        return Ok(None);
+      }
+    };
+    let command = command.name();
+
+    if self.requested.contains(&command) {
+      match fields.duplicates() {
+        DuplicatePolicy::Warn => warn!("Server requested {} more than once", command),
+        DuplicatePolicy::Reject => return Err::duplicate_command(&command),
+      }
+    }
+
+    self.requested.push(command.clone());
+
+    let (name, datum) = match fields.resolve(&command, &mut self.counted, userdata)? {
+      Some(resolved) => resolved,
+      None => return Ok(None),
+    };
+    debug!("{} -> {}", command, name);
+
+    self.answered.push(name.clone());
+
+    Ok(Some(Field { command, name, hash, datum }))
+  }
+
+  /// Produce the completeness report of the data phase, when the server sends END.
+  pub(crate) fn report(&mut self, fields: &FieldMap, userdata: &UserData) -> Report {
+    let requested = std::mem::take(&mut self.requested);
+    let answered = std::mem::take(&mut self.answered);
+    fields.report(requested, answered, userdata)
+  }
+}
+
+/// Parse a line received from the server, turning an ERROR command into an error that
+/// keeps the state it arrived in.
+pub(crate) fn parse_command<'a>(line: &'a [u8], state: &'static str) -> Res<ServerCommand<'a>> {
+  let line = std::str::from_utf8(line)?;
+
+  match ServerCommand::parse(line)? {
+    ServerCommand::Error(msg) => Err::server(state, msg),
+    command => Ok(command),
+  }
 }
 
-pub struct Exasol<S: State = ()> {
-  stream: SslStream<TcpStream>,
+pub struct Exasol<S: State = (), T: Read + Write = TlsStream> {
+  stream: T,
+  endpoint: Endpoint,
+  transcript: Option<Transcript>,
+  observers: Vec<Box<dyn SessionObserver>>,
+  fields: FieldMap,
+  solver: Box<dyn Solver>,
+  cancel: CancelToken,
+  read_timeout: Duration,
+  close: Option<fn(&mut T) -> Res<()>>,
   buffer: Vec<u8>,
   state: S,
 }
 
-impl<S1: State> Exasol<S1> {
-  fn make<S0: State>(old: Exasol<S0>) -> Self {
+impl<S1: State, T: Read + Write> Exasol<S1, T> {
+  fn make<S0: State>(old: Exasol<S0, T>) -> Res<Self> {
     Self::make_with_state(old, Default::default())
   }
 
-  fn make_with_state<S0: State>(old: Exasol<S0>, new_state: S1) -> Self {
-    Self { stream: old.stream, buffer: old.buffer, state: new_state }
+  fn make_with_state<S0: State>(old: Exasol<S0, T>, new_state: S1) -> Res<Self> {
+    let mut new = Self {
+      stream: old.stream,
+      endpoint: old.endpoint,
+      transcript: old.transcript,
+      observers: old.observers,
+      fields: old.fields,
+      solver: old.solver,
+      cancel: old.cancel,
+      read_timeout: old.read_timeout,
+      close: old.close,
+      buffer: old.buffer,
+      state: new_state,
+    };
+
+    new.notify(|observer| observer.state_changed(S0::NAME, S1::NAME));
+    new.record(&Event::State { state: S1::NAME })?;
+
+    Ok(new)
   }
 }
 
-impl<S: State> Exasol<S> {
-  fn get_command_args_and_state(&mut self) -> Res<(&str, Split<char>, &S)> {
-    crate::io::read_until(&mut self.stream, &mut self.buffer, |b| b == b'\n')?;
-    let buffer_str = std::str::from_utf8(&self.buffer)?;
-    let mut args = buffer_str.trim().split(' ');
-    let command = args.next().ok_or(Err::CommandExpected)?;
+impl<S: State, T: Read + Write> Exasol<S, T> {
+  /// Get a reference to the endpoint the session is connected to.
+  pub fn endpoint(&self) -> &Endpoint {
+    &self.endpoint
+  }
+
+  /// Record the session to the given transcript.
+  pub fn with_transcript(mut self, mut transcript: Transcript) -> Res<Self> {
+    transcript.record(&Event::Connected { endpoint: &self.endpoint.to_string() })?;
+    self.transcript = Some(transcript);
+    Ok(self)
+  }
 
-    if command == "ERROR" {
-      let msg = args.collect::<Vec<&str>>().join(" ");
-      return Err::server(msg);
+  /// Report the events of the session to the given observer.
+  pub fn with_observer(mut self, observer: impl SessionObserver + 'static) -> Self {
+    self.observers.push(Box::new(observer));
+    self
+  }
+
+  /// Answer the data commands of the server using the given field mapping instead of the
+  /// built-in one.
+  pub fn with_fields(mut self, fields: FieldMap) -> Self {
+    self.fields = fields;
+    self
+  }
+
+  /// Solve the proof-of-work with the given solver instead of the built-in one.
+  pub fn with_solver(mut self, solver: impl Solver + 'static) -> Self {
+    self.solver = Box::new(solver);
+    self
+  }
+
+  /// Stop the session when the given token is cancelled: the proof-of-work search is
+  /// stopped, the next read from the server fails and the TLS session is closed.
+  pub fn with_cancel(mut self, cancel: CancelToken) -> Self {
+    self.cancel = cancel;
+    self
+  }
+
+  /// Fail the session when the server sends nothing for the given time while the client
+  /// waits for a line, or when the TLS handshake takes longer, instead of after the default
+  /// of one minute.
+  pub fn with_read_timeout(mut self, read_timeout: Duration) -> Self {
+    self.read_timeout = read_timeout;
+    self
+  }
+
+  pub(crate) fn into_stream(self) -> T {
+    self.stream
+  }
+
+  fn notify(&mut self, mut event: impl FnMut(&mut dyn SessionObserver)) {
+    for observer in &mut self.observers {
+      event(observer.as_mut());
     }
+  }
 
-    Ok((command, args, &self.state))
+  /// Report an error of the session to the observers before returning it. If the session
+  /// was cancelled, the TLS session is also closed.
+  fn observe<R>(&mut self, res: Res<R>) -> Res<R> {
+    if let Err(e) = &res {
+      self.notify(|observer| observer.error(e.as_ref()));
+
+      if self.cancel.is_cancelled() {
+        self.close();
+      }
+    }
+
+    res
+  }
+
+  fn close(&mut self) {
+    if let Some(close) = self.close.take() {
+      if let Err(e) = close(&mut self.stream) {
+        warn!("Could not close the session cleanly: {}", e);
+      }
+    }
+  }
+
+  fn record(&mut self, event: &Event) -> Res<()> {
+    if let Some(transcript) = &mut self.transcript {
+      transcript.record(event)?;
+    }
+
+    Ok(())
+  }
+
+  fn send_line(&mut self, line: &str) -> Res<()> {
+    self.write_line(line)?;
+    self.sent(line)
+  }
+
+  fn write_line(&mut self, line: &str) -> Res<()> {
+    self.stream.write_all(line.as_bytes())?;
+    self.stream.write_all(b"\n")?;
+    self.stream.flush()?;
+    Ok(())
+  }
+
+  fn sent(&mut self, line: &str) -> Res<()> {
+    self.notify(|observer| observer.line_sent(line));
+    self.record(&Event::Sent { line })
+  }
+
+  /// Send the OK acknowledging the server's END. Observers learn about it as soon as it is
+  /// flushed, so a failure recording or reporting it cannot hide that the server has it.
+  fn acknowledge_end(&mut self) -> Res<()> {
+    self.write_line("OK")?;
+    self.notify(|observer| observer.end_acknowledged());
+    self.sent("OK")
+  }
+
+  /// Acknowledge an END the server sent before the data phase and fail with the state it
+  /// arrived in.
+  fn ended<R>(&mut self) -> Res<R> {
+    self.acknowledge_end()?;
+    Err::server_ended(S::NAME)
+  }
+
+  /// Read the next line from the server into the buffer. Reads timing out are resumed
+  /// unless the session was cancelled in the meantime, or nothing was received for longer
+  /// than the read timeout.
+  fn read_line(&mut self) -> Res<()> {
+    self.buffer.clear();
+    let mut last_received = Instant::now();
+
+    loop {
+      let received = self.buffer.len();
+
+      match crate::io::read_until(&mut self.stream, &mut self.buffer, |b| b == b'\n') {
+        Ok(()) => break,
+        Err(e) if is_interruption(e.kind()) => {
+          if self.cancel.is_cancelled() {
+            return Err::cancelled();
+          }
+
+          if self.buffer.len() > received {
+            last_received = Instant::now();
+          } else if last_received.elapsed() >= self.read_timeout {
+            return Err::timeout("data from the server", self.read_timeout);
+          }
+        }
+        Err(e) => return Err(e.into()),
+      }
+    }
+
+    let line = std::str::from_utf8(&self.buffer)?.trim_end_matches('\r');
+
+    for observer in &mut self.observers {
+      observer.line_received(line);
+    }
+
+    if let Some(transcript) = &mut self.transcript {
+      transcript.record(&Event::Received { line })?;
+    }
+
+    Ok(())
   }
 }
 
 impl Exasol {
-  pub fn new(cert_file: &Path, keylog_file: Option<&Path>, address: &str) -> Res<Self> {
-    let stream = create_ssl_stream(cert_file, keylog_file, address)?;
-    Ok(Self { stream, buffer: vec![], state: Default::default() })
+  pub fn new(tls: &TlsConfig, endpoints: &Endpoints) -> Res<Self> {
+    let (stream, endpoint) = <TlsStream as TlsSession>::connect(tls, endpoints)?;
+    Ok(Self {
+      stream,
+      endpoint,
+      transcript: None,
+      observers: vec![],
+      fields: FieldMap::builtin(),
+      solver: Box::new(ParallelSolver),
+      cancel: CancelToken::new(),
+      read_timeout: DEFAULT_READ_TIMEOUT,
+      close: Some(<TlsStream as TlsSession>::close_notify),
+      buffer: vec![],
+      state: Default::default(),
+    })
   }
 
   pub fn connect(mut self) -> Res<Exasol<StateHelo>> {
-    self.stream.connect()?;
+    let res = self.handshake_tls();
+    self.observe(res)?;
     debug!("SSL stream connected");
-    Ok(Exasol::make(self))
+
+    let endpoint = self.endpoint.clone();
+    self.notify(|observer| observer.connected(&endpoint));
+
+    Exasol::make(self)
   }
-}
 
-impl Exasol<StateHelo> {
-  pub fn handshake(mut self) -> Res<Exasol<StatePow>> {
-    let (command, _, _) = self.get_command_args_and_state()?;
+  /// Run the TLS handshake, resuming it when a read times out unless the session was
+  /// cancelled in the meantime or the read timeout has passed since it started.
+  fn handshake_tls(&mut self) -> Res<()> {
+    let start = Instant::now();
 
-    if command != "HELO" {
-      return Err::unknown_or_unexpected_command(command, "HELO");
-    }
+    while !self.stream.handshake()? {
+      if self.cancel.is_cancelled() {
+        return Err::cancelled();
+      }
 
-    self.stream.write_all(b"EHLO\n")?;
-    self.stream.flush()?;
+      if start.elapsed() >= self.read_timeout {
+        return Err::timeout("the TLS handshake", self.read_timeout);
+      }
+    }
 
-    Ok(Exasol::make(self))
+    Ok(())
   }
 }
 
-impl Exasol<StatePow> {
-  pub fn pow(mut self) -> Res<Exasol<StateData>> {
-    let (command, mut args, _) = self.get_command_args_and_state()?;
+impl<T: Read + Write> Exasol<StateHelo, T> {
+  /// Run a session over an already established stream (e.g. an in-memory stream), starting
+  /// at the handshake.
+  pub fn from_stream(stream: T, endpoint: Endpoint) -> Self {
+    Self {
+      stream,
+      endpoint,
+      transcript: None,
+      observers: vec![],
+      fields: FieldMap::builtin(),
+      solver: Box::new(ParallelSolver),
+      cancel: CancelToken::new(),
+      read_timeout: DEFAULT_READ_TIMEOUT,
+      close: None,
+      buffer: vec![],
+      state: Default::default(),
+    }
+  }
+
+  pub fn handshake(mut self) -> Res<Exasol<StatePow, T>> {
+    let res = self.helo();
+    self.observe(res)?;
+    Exasol::make(self)
+  }
 
-    if command != "POW" {
-      return Err::unknown_or_unexpected_command(command, "POW");
+  fn helo(&mut self) -> Res<()> {
+    self.read_line()?;
+
+    match parse_command(&self.buffer, StateHelo::NAME)? {
+      ServerCommand::Helo => self.send_line("EHLO"),
+      ServerCommand::End => self.ended(),
+      command => Err::unknown_or_unexpected_command(&command.name(), "HELO"),
     }
+  }
+}
 
-    let authdata = args.next().ok_or(Err::MissingArg)?;
-    let difficulty = {
-      let val = args.next().ok_or(Err::MissingArg)?.parse::<usize>()?;
-      if val > 9 {
-        return Err::invalid_difficulty(val);
-      }
-      val
+impl<T: Read + Write> Exasol<StatePow, T> {
+  pub fn pow(mut self) -> Res<Exasol<StateData, T>> {
+    let res = self.solve();
+    let new_state = self.observe(res)?;
+    Exasol::make_with_state(self, new_state)
+  }
+
+  fn solve(&mut self) -> Res<StateData> {
+    self.read_line()?;
+
+    let (new_state, difficulty) = match parse_command(&self.buffer, StatePow::NAME)? {
+      ServerCommand::Pow { authdata, difficulty } => (StateData::new(authdata), difficulty),
+      ServerCommand::End => return self.ended(),
+      command => return Err::unknown_or_unexpected_command(&command.name(), "POW"),
     };
 
-    // We create the new state here to avoid an authdata clone.
-    let new_state = StateData::new(authdata);
+    let authdata = new_state.authdata();
 
-    debug!("Authdata = {}  |  Difficulty = {}", authdata, difficulty);
+    self.notify(|observer| observer.pow_started(authdata, difficulty));
 
-    let random_bytes = pow(authdata, difficulty)?.ok_or(Err::CannotPow)?;
+    let random_bytes = {
+      let observers = parking_lot::Mutex::new(&mut self.observers);
+      let progress = |iterations, elapsed| {
+        for observer in observers.lock().iter_mut() {
+          observer.pow_progress(iterations, elapsed);
+        }
+      };
+
+      match self.solver.solve(authdata, difficulty, self.cancel.flag(), &progress)? {
+        Some(random_bytes) => random_bytes,
+        None if self.cancel.is_cancelled() => return Err(Box::new(Err::PowCancelled)),
+        None => return Err(Box::new(Err::CannotPow)),
+      }
+    };
     let random_string = std::str::from_utf8(&random_bytes)?;
 
     assert!(!random_bytes.is_empty());
     assert!(!random_string.is_empty());
 
-    self.stream.write_all(random_string.as_bytes())?;
-    self.stream.write_all(b"\n")?;
-    self.stream.flush()?;
+    self.send_line(random_string)?;
 
-    Ok(Exasol::make_with_state(self, new_state))
+    let digest = hex::encode(new_state.hash(random_string));
+    self.notify(|observer| observer.pow_solved(random_string, &digest));
+    self.record(&Event::Pow { authdata, difficulty, suffix: random_string, digest: &digest })?;
+
+    Ok(new_state)
   }
 }
 
-impl Exasol<StateData> {
-  pub fn submit(mut self, userdata: &UserData) -> Res<Exasol<StateEnd>> {
-    let userdata_skype = userdata.skype_as_str();
-    let userdata_birthdate = &userdata.birth_date_as_string();
-    let userdata_mailnum = userdata.emails_num();
-    let userdata_mailnum_as_string = &userdata_mailnum.to_string();
-    let userdata_addrnum = userdata.address_num();
-    let userdata_addrnum_as_string = &userdata_addrnum.to_string();
+/// A user data field the client answers a data command with.
+pub struct Field<'u> {
+  command: String,
+  name: String,
+  hash: [u8; 20],
+  datum: Cow<'u, str>,
+}
 
-    loop {
-      let got_mailnum = self.state.got_mailnum();
-      let got_addrnum = self.state.got_addrnum();
-      let (command, mut args, state) = self.get_command_args_and_state()?;
+impl Field<'_> {
+  /// Get a reference to the server command the field answers.
+  pub fn command(&self) -> &str {
+    &self.command
+  }
 
-      if command == "END" {
-        self.stream.write_all(b"OK\n")?;
-        self.stream.flush()?;
+  /// Get a reference to the name of the user data field.
+  pub fn name(&self) -> &str {
+    &self.name
+  }
 
-        info!("Successfully submitted data to server");
-        return Ok(Exasol::make(self));
-      }
+  /// Get the hash sent along with the field, hex-encoded.
+  pub fn hash(&self) -> String {
+    hex::encode(self.hash)
+  }
 
-      let arg1 = args.next().ok_or(Err::MissingArg)?;
-      let hash = state.hash(arg1);
+  /// Get a reference to the value of the field.
+  pub fn datum(&self) -> &str {
+    &self.datum
+  }
+}
 
-      let datum = match command {
-        "NAME" => {
-          debug!("Name:");
-          userdata.name()
-        }
-        "SKYPE" => {
-          debug!("Skype:");
-          userdata_skype
-        }
-        "COUNTRY" => {
-          debug!("Country:");
-          userdata.country()
-        }
-        "BIRTHDATE" => {
-          debug!("Birthdate:");
-          userdata_birthdate
-        }
-        "MAILNUM" => {
-          debug!("Mailnum:");
-          self.state.set_got_mailnum();
-          userdata_mailnum_as_string
+impl<T: Read + Write> Exasol<StateEnd, T> {
+  /// Get a reference to the completeness report of the data phase.
+  pub fn report(&self) -> &Report {
+    self.state.report()
+  }
+}
+
+impl<T: Read + Write> Exasol<StateData, T> {
+  pub fn submit(mut self, userdata: &UserData) -> Res<Exasol<StateEnd, T>> {
+    let res = self.submit_fields(userdata);
+    let report = self.observe(res)?;
+    Exasol::make_with_state(self, StateEnd::new(report))
+  }
+
+  fn submit_fields(&mut self, userdata: &UserData) -> Res<Report> {
+    loop {
+      let field = match self.next_field(userdata)? {
+        Some(field) => field,
+        None => {
+          self.acknowledge_end()?;
+          let report = self.report(userdata)?;
+          self.notify(|observer| observer.end());
+          return Ok(report);
         }
-        "ADDRNUM" => {
-          debug!("Addrnum:");
-          self.state.set_got_addrnum();
-          userdata_addrnum_as_string
+      };
+
+      self.send_response(&field.command, &field.hash(), &field.datum)?;
+      self.notify(|observer| observer.field_submitted(&field));
+    }
+  }
+
+  /// Go through the data phase reporting every field that would be submitted. If `send` is
+  /// false, the session stops before any data line is sent, so only the field for the first
+  /// data command is reported.
+  pub fn dry_run(mut self, userdata: &UserData, send: bool, report: impl FnMut(&Field)) -> Res<()> {
+    let res = self.dry_run_fields(userdata, send, report);
+    self.observe(res)
+  }
+
+  fn dry_run_fields(&mut self, userdata: &UserData, send: bool, mut report: impl FnMut(&Field)) -> Res<()> {
+    loop {
+      let field = match self.next_field(userdata)? {
+        Some(field) => field,
+        None if send => {
+          self.acknowledge_end()?;
+          self.report(userdata)?;
+
+          info!("Dry run completed");
+          return Ok(());
         }
-        command if command.starts_with("MAIL") => {
-          debug!("MailN ({})", command);
+        None => return Ok(()),
+      };
 
-          if !got_mailnum {
-            return Err::no_mailnum();
-          }
+      report(&field);
 
-          let num = command[4..].parse::<usize>()?;
+      if !send {
+        info!("Dry run stopped before sending any data");
+        return Ok(());
+      }
 
-          if num < 1 || num > userdata_mailnum {
-            return Err::invalid_mail_index(num);
-          }
+      self.send_response(&field.command, &field.hash(), &field.datum)?;
+      self.notify(|observer| observer.field_submitted(&field));
+    }
+  }
 
-          userdata.email(num - 1)
-        }
-        command if command.starts_with("ADDRLINE") => {
-          debug!("AddrN ({})", command);
+  /// Produce the completeness report of the data phase and pass it to the observers.
+  fn report(&mut self, userdata: &UserData) -> Res<Report> {
+    let report = self.state.report(&self.fields, userdata);
+    self.notify(|observer| observer.report(&report));
+    self.record(&Event::Report { missing: report.missing(), duplicates: report.duplicates() })?;
+    Ok(report)
+  }
 
-          if !got_addrnum {
-            return Err::no_addrnum();
-          }
+  /// Read the next data command and find the user data field it asks for, or `None` when
+  /// the server sends END.
+  fn next_field<'u>(&mut self, userdata: &'u UserData) -> Res<Option<Field<'u>>> {
+    loop {
+      self.read_line()?;
 
-          let num = command[8..].parse::<usize>()?;
+      let field = match parse_command(&self.buffer, StateData::NAME)? {
+        ServerCommand::End => return Ok(None),
+        command => self.state.field(&command, &self.fields, userdata)?,
+      };
 
-          if num < 1 || num > userdata_addrnum {
-            return Err::invalid_address_index(num);
-          }
+      if field.is_some() {
+        return Ok(field);
+      }
+    }
+  }
 
-          userdata.address_line(num - 1)
-        }
-        _ => {
-          const EXPECTED: &str = "NAME|MAILNUM|MAILx|SKYPE|BIRTHDATE|COUNTRY|ADDRNUM|ADDRx";
-          return Err::unknown_or_unexpected_command(command, EXPECTED);
-        }
+  fn send_response(&mut self, command: &str, hash: &str, datum: &str) -> Res<()> {
+    self.stream.write_all(hash.as_bytes())?;
+    self.stream.write_all(b" ")?;
+    self.stream.write_all(datum.as_bytes())?;
+    self.stream.write_all(b"\n")?;
+    self.stream.flush()?;
+
+    let line = format!("{} {}", hash, datum);
+    self.notify(|observer| observer.line_sent(&line));
+
+    if let Some(transcript) = &mut self.transcript {
+      let datum = if transcript.redact() {
+        None
+      } else {
+        Some(datum)
       };
+      let line = format!("{} {}", hash, datum.unwrap_or(REDACTED));
+      transcript.record(&Event::Sent { line: &line })?;
+      transcript.record(&Event::Response { command, hash, datum })?;
+    }
 
-      debug!("  Submitting `{}`", datum);
+    Ok(())
+  }
+}
 
-      self.stream.write_all(hex::encode(hash).as_bytes())?;
-      self.stream.write_all(b" ")?;
-      self.stream.write_all(datum.as_bytes())?;
-      self.stream.write_all(b"\n")?;
-      self.stream.flush()?;
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::replay::ReplayStream;
+  use std::error::Error;
+  use std::sync::atomic::AtomicBool;
+  use std::sync::Arc;
+
+  /// Returns the same suffix for any proof-of-work, which the in-memory server accepts.
+  struct FixedSolver;
+
+  impl Solver for FixedSolver {
+    fn solve(
+      &self,
+      _authdata: &str,
+      _difficulty: usize,
+      _cancel: &AtomicBool,
+      _progress: &(dyn Fn(usize, Duration) + Sync),
+    ) -> Res<Option<Vec<u8>>> {
+      Ok(Some(b"suffix".to_vec()))
     }
   }
+
+  /// Records the lines the client sent and the errors the session failed with.
+  #[derive(Clone, Default)]
+  struct Recorder(Arc<parking_lot::Mutex<(Vec<String>, Vec<String>)>>);
+
+  impl SessionObserver for Recorder {
+    fn line_sent(&mut self, line: &str) {
+      self.0.lock().0.push(line.to_string());
+    }
+
+    fn error(&mut self, error: &dyn Error) {
+      self.0.lock().1.push(error.to_string());
+    }
+  }
+
+  fn session(lines: &[&str], recorder: &Recorder) -> Exasol<StateHelo, ReplayStream> {
+    let stream = ReplayStream::new(lines.iter().map(|line| line.to_string()));
+    Exasol::from_stream(stream, Endpoint::new("test", 0))
+      .with_solver(FixedSolver)
+      .with_observer(recorder.clone())
+  }
+
+  fn userdata() -> UserData {
+    serde_json::from_str(
+      r#"{"name": "Jane Doe", "emails": ["jane@example.com", "jd@example.com"],
+          "birth_date": "01.02.1990", "country": "Germany", "address": ["Street 1"]}"#,
+    )
+    .unwrap()
+  }
+
+  fn assert_server_error(error: Box<dyn Error>, state: &str, message: &str) {
+    match error.downcast_ref::<Err>() {
+      Some(Err::Server(actual_state, actual_message)) => {
+        assert_eq!(*actual_state, state);
+        assert_eq!(actual_message, message);
+      }
+      _ => panic!("expected a server error, got: {}", error),
+    }
+  }
+
+  #[test]
+  fn error_during_helo() {
+    let recorder = Recorder::default();
+    let error = session(&["ERROR bad client"], &recorder).handshake().err().unwrap();
+
+    assert_server_error(error, "HELO", "bad client");
+    let (sent, errors) = &*recorder.0.lock();
+    assert!(sent.is_empty());
+    assert_eq!(errors.len(), 1);
+  }
+
+  #[test]
+  fn error_during_pow() {
+    let recorder = Recorder::default();
+    let error = session(&["HELO", "ERROR pow unavailable"], &recorder)
+      .handshake()
+      .unwrap()
+      .pow()
+      .err()
+      .unwrap();
+
+    assert_server_error(error, "POW", "pow unavailable");
+    let (sent, errors) = &*recorder.0.lock();
+    assert_eq!(sent, &["EHLO"]);
+    assert_eq!(errors.len(), 1);
+  }
+
+  #[test]
+  fn error_during_data() {
+    let recorder = Recorder::default();
+    let error = session(&["HELO", "POW authdata 1", "NAME abc", "ERROR invalid data"], &recorder)
+      .handshake()
+      .unwrap()
+      .pow()
+      .unwrap()
+      .submit(&userdata())
+      .err()
+      .unwrap();
+
+    assert_server_error(error, "DATA", "invalid data");
+    let (sent, errors) = &*recorder.0.lock();
+    assert_eq!(sent.len(), 3);
+    assert_eq!(sent[1], "suffix");
+    assert!(sent[2].ends_with(" Jane Doe"));
+    assert_eq!(errors.len(), 1);
+  }
+
+  #[test]
+  fn closed_connection_fails_with_unexpected_eof() {
+    let recorder = Recorder::default();
+    let error = session(&["HELO"], &recorder).handshake().unwrap().pow().err().unwrap();
+
+    let error = error.downcast_ref::<std::io::Error>().map(std::io::Error::kind);
+    assert_eq!(error, Some(std::io::ErrorKind::UnexpectedEof));
+  }
+
+  #[test]
+  fn end_during_data_is_acknowledged() {
+    let recorder = Recorder::default();
+    let session = session(&["HELO", "POW authdata 1", "NAME abc", "END"], &recorder)
+      .handshake()
+      .unwrap()
+      .pow()
+      .unwrap()
+      .submit(&userdata())
+      .unwrap();
+
+    assert!(!session.report().is_complete());
+    assert_eq!(recorder.0.lock().0.last().map(String::as_str), Some("OK"));
+  }
+
+  /// Run the data phase of a session after the handshake and proof-of-work.
+  fn submit(data: &[&str], fields: FieldMap, recorder: &Recorder) -> Res<Exasol<StateEnd, ReplayStream>> {
+    let lines: Vec<&str> = ["HELO", "POW authdata 1"].iter().chain(data).copied().collect();
+    session(&lines, recorder)
+      .with_fields(fields)
+      .handshake()?
+      .pow()?
+      .submit(&userdata())
+  }
+
+  const COMPLETE: &[&str] = &[
+    "MAILNUM a",
+    "MAIL1 b",
+    "MAIL2 c",
+    "SKYPE d",
+    "BIRTHDATE e",
+    "COUNTRY f",
+    "ADDRNUM g",
+    "ADDRLINE1 h",
+    "END",
+  ];
+
+  #[test]
+  fn duplicate_is_answered_again_under_warn() {
+    let recorder = Recorder::default();
+    let data: Vec<&str> = ["NAME x", "NAME y"].iter().chain(COMPLETE).copied().collect();
+    let session = submit(&data, FieldMap::builtin(), &recorder).unwrap();
+
+    let report = session.report();
+    assert_eq!(report.duplicates(), ["NAME"]);
+    assert!(report.missing().is_empty());
+    assert!(!report.is_complete());
+    let sent = &recorder.0.lock().0;
+    assert!(sent[2].ends_with(" Jane Doe") && sent[3].ends_with(" Jane Doe"));
+  }
+
+  #[test]
+  fn duplicate_aborts_under_reject() {
+    let recorder = Recorder::default();
+    let fields = FieldMap::builtin().with_duplicates(DuplicatePolicy::Reject);
+    let error = submit(&["NAME x", "NAME y"], fields, &recorder).err().unwrap();
+
+    assert!(matches!(error.downcast_ref::<Err>(), Some(Err::DuplicateCommand(command)) if command == "NAME"));
+    assert_eq!(recorder.0.lock().0.len(), 3);
+  }
+
+  #[test]
+  fn item_beyond_count_aborts() {
+    let recorder = Recorder::default();
+    let error = submit(&["NAME x", "MAILNUM a", "MAIL3 b"], FieldMap::builtin(), &recorder)
+      .err()
+      .unwrap();
+
+    assert!(matches!(error.downcast_ref::<Err>(), Some(Err::InvalidIndex(item, 3)) if item == "MAIL"));
+    let sent = &recorder.0.lock().0;
+    assert!(sent.last().unwrap().ends_with(" 2"));
+  }
+
+  #[test]
+  fn end_before_required_fields_reports_them() {
+    let recorder = Recorder::default();
+    let session = submit(&["NAME x", "MAILNUM a", "MAIL1 b", "END"], FieldMap::builtin(), &recorder).unwrap();
+
+    let report = session.report();
+    assert_eq!(report.requested(), ["NAME", "MAILNUM", "MAIL1"]);
+    assert_eq!(
+      report.missing(),
+      [
+        "MAIL2",
+        "SKYPE",
+        "BIRTHDATE",
+        "COUNTRY",
+        "ADDRNUM",
+        "ADDRLINE1"
+      ]
+    );
+    assert!(report.duplicates().is_empty());
+    assert!(!report.is_complete());
+    assert_eq!(recorder.0.lock().0.last().map(String::as_str), Some("OK"));
+  }
+
+  #[test]
+  fn complete_session_reports_no_gaps() {
+    let recorder = Recorder::default();
+    let data: Vec<&str> = ["NAME x"].iter().chain(COMPLETE).copied().collect();
+    let session = submit(&data, FieldMap::builtin(), &recorder).unwrap();
+
+    assert!(session.report().is_complete());
+  }
 }
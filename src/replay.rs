@@ -0,0 +1,448 @@
+#![warn(clippy::all)]
+
+use crate::command::ServerCommand;
+use crate::endpoint::Endpoint;
+use crate::error::Err;
+use crate::fields::FieldMap;
+use crate::pow::verify;
+use crate::protocol::Exasol;
+use crate::solver::Solver;
+use crate::transcript::REDACTED;
+use crate::userdata::UserData;
+use crate::Res;
+use log::{debug, info};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+
+#[derive(Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum RecordedEvent {
+  Attempt {},
+  Connected {
+    endpoint: Endpoint,
+  },
+  Received {
+    line: String,
+  },
+  Sent {
+    line: String,
+  },
+  #[serde(other)]
+  Other,
+}
+
+/// A line sent by the client in a recorded session.
+#[derive(Clone)]
+enum Expected {
+  /// A line that must be sent again byte for byte (apart from redacted data).
+  Line(String),
+  /// A proof-of-work suffix, which only needs to be valid for the recorded authdata and
+  /// difficulty.
+  Pow { authdata: String, difficulty: usize, suffix: String },
+}
+
+/// The lines exchanged in a session, as recorded in a transcript.
+pub struct Recording {
+  endpoint: Endpoint,
+  received: Vec<String>,
+  sent: Vec<Expected>,
+}
+
+impl Recording {
+  pub fn new(filename: &Path) -> Res<Self> {
+    debug!("Reading transcript from {}", filename.display());
+
+    let file = File::open(filename)?;
+    let reader = BufReader::new(file);
+
+    let mut endpoint = None;
+    let mut received = vec![];
+    let mut sent = vec![];
+    let mut pow = None;
+
+    for line in reader.lines() {
+      let line = line?;
+
+      if line.trim().is_empty() {
+        continue;
+      }
+
+      match serde_json::from_str(&line).map_err(Box::new)? {
+        // Only the last attempt of a retried session is replayed.
+        RecordedEvent::Attempt {} => {
+          endpoint = None;
+          received.clear();
+          sent.clear();
+          pow = None;
+        }
+        RecordedEvent::Connected { endpoint: recorded } => endpoint = Some(recorded),
+        RecordedEvent::Received { line } => {
+          if let Ok(ServerCommand::Pow { authdata, difficulty }) = ServerCommand::parse(&line) {
+            pow = Some((authdata.to_string(), difficulty));
+          }
+
+          received.push(line);
+        }
+        RecordedEvent::Sent { line } => sent.push(match pow.take() {
+          Some((authdata, difficulty)) => Expected::Pow { authdata, difficulty, suffix: line },
+          None => Expected::Line(line),
+        }),
+        RecordedEvent::Other => {}
+      }
+    }
+
+    debug!("Read transcript: {} received and {} sent lines", received.len(), sent.len());
+
+    let endpoint = endpoint.unwrap_or_else(|| Endpoint::new("replay", 0));
+    Ok(Self { endpoint, received, sent })
+  }
+
+  /// Get a reference to the endpoint the session was recorded against.
+  pub fn endpoint(&self) -> &Endpoint {
+    &self.endpoint
+  }
+
+  /// Get the lines received from the server, in order.
+  pub fn received(&self) -> &[String] {
+    &self.received
+  }
+
+  /// Create a solver that returns the recorded proof-of-work suffixes, so that the search
+  /// itself is not replayed.
+  pub fn solver(&self) -> RecordedSolver {
+    let suffixes = self
+      .sent
+      .iter()
+      .filter_map(|expected| match expected {
+        Expected::Pow { authdata, suffix, .. } => Some((authdata.clone(), suffix.clone())),
+        Expected::Line(_) => None,
+      })
+      .collect();
+
+    RecordedSolver { suffixes }
+  }
+
+  /// Create an in-memory stream that feeds the recorded server lines to the client. Once
+  /// they run out, reads fail with the first difference between the lines the client sent and
+  /// the recorded ones.
+  pub fn stream(&self) -> ReplayStream {
+    let mut stream = ReplayStream::new(self.received.iter().cloned());
+    stream.sent = Some(self.sent.clone());
+    stream
+  }
+
+  /// Check the lines the client sent against the recorded ones.
+  pub fn check(&self, written: &[u8]) -> Res<()> {
+    match mismatch(&self.sent, written) {
+      Some(error) => Err(Box::new(error)),
+      None => Ok(()),
+    }
+  }
+}
+
+/// Find the first line the client sent differently from the recorded ones, including
+/// recorded lines it did not send and lines sent beyond them.
+fn mismatch(sent: &[Expected], written: &[u8]) -> Option<Err> {
+  let written = String::from_utf8_lossy(written);
+  let written: Vec<&str> = written.strip_suffix('\n').unwrap_or(&written).split('\n').collect();
+
+  for (index, expected) in sent.iter().enumerate() {
+    let actual = written.get(index).copied().unwrap_or_default();
+
+    match expected {
+      Expected::Line(expected) => {
+        let matches = match expected.strip_suffix(REDACTED) {
+          Some(prefix) => actual.starts_with(prefix),
+          None => actual == expected,
+        };
+
+        if !matches {
+          return Some(Err::ReplayMismatch(index + 1, expected.clone(), actual.to_string()));
+        }
+      }
+      Expected::Pow { authdata, difficulty, .. } => {
+        if !verify(authdata, actual.as_bytes(), *difficulty) {
+          return Some(Err::ReplayInvalidPow(index + 1, actual.to_string()));
+        }
+      }
+    }
+  }
+
+  if written.len() > sent.len() {
+    return Some(Err::ReplayMismatch(sent.len() + 1, String::new(), written[sent.len()].to_string()));
+  }
+
+  None
+}
+
+/// A solver that returns the suffix recorded for the authdata instead of searching for one,
+/// so that replaying a session takes no time whatever its difficulty. As the suffix is only
+/// echoed, the proof-of-work search of the client is not replayed with it.
+pub struct RecordedSolver {
+  suffixes: Vec<(String, String)>,
+}
+
+impl Solver for RecordedSolver {
+  fn solve(
+    &self,
+    authdata: &str,
+    _difficulty: usize,
+    _cancel: &AtomicBool,
+    _progress: &(dyn Fn(usize, Duration) + Sync),
+  ) -> Res<Option<Vec<u8>>> {
+    match self.suffixes.iter().find(|(recorded, _)| recorded == authdata) {
+      Some((_, suffix)) => Ok(Some(suffix.as_bytes().to_vec())),
+      None => Err::replay_no_pow(authdata),
+    }
+  }
+}
+
+/// An in-memory stream that returns one recorded server line per read and collects
+/// everything the client writes.
+pub struct ReplayStream {
+  lines: VecDeque<String>,
+  pending: Vec<u8>,
+  written: Vec<u8>,
+  sent: Option<Vec<Expected>>,
+}
+
+impl ReplayStream {
+  /// Create a stream that ends once the lines run out.
+  pub fn new(lines: impl IntoIterator<Item = String>) -> Self {
+    Self { lines: lines.into_iter().collect(), pending: vec![], written: vec![], sent: None }
+  }
+
+  /// Get a reference to everything the client wrote to the stream.
+  pub fn written(&self) -> &[u8] {
+    &self.written
+  }
+}
+
+impl Read for ReplayStream {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if self.pending.is_empty() {
+      match self.lines.pop_front() {
+        Some(line) => {
+          self.pending.extend_from_slice(line.as_bytes());
+          self.pending.push(b'\n');
+        }
+        None => match &self.sent {
+          // The client waits for a line the session never sent, so it responded differently.
+          Some(sent) => {
+            let lines = self.written.iter().filter(|&&byte| byte == b'\n').count();
+            let error = mismatch(sent, &self.written)
+              .unwrap_or_else(|| Err::ReplayMismatch(lines + 1, String::new(), String::new()));
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, error));
+          }
+          None => return Ok(0),
+        },
+      }
+    }
+
+    let len = buf.len().min(self.pending.len());
+    buf[..len].copy_from_slice(&self.pending[..len]);
+    self.pending.drain(..len);
+    Ok(len)
+  }
+}
+
+impl Write for ReplayStream {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.written.extend_from_slice(buf);
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+/// Feed the server lines of a recorded session to the client and check that it responds
+/// exactly as recorded. With the solver of `Recording::solver`, the recorded proof-of-work
+/// suffix is sent again, so that only the recording is checked for validity. Any other solver
+/// searches for a new suffix, which is checked against the recorded authdata and difficulty.
+pub fn replay(
+  recording: &Recording,
+  userdata: &UserData,
+  fields: &FieldMap,
+  solver: impl Solver + 'static,
+) -> Res<()> {
+  let stream = recording.stream();
+
+  let session = Exasol::from_stream(stream, recording.endpoint().clone())
+    .with_fields(fields.clone())
+    .with_solver(solver)
+    .handshake()
+    .and_then(|session| session.pow())
+    .and_then(|session| session.submit(userdata))
+    .map_err(unwrap_mismatch)?;
+
+  recording.check(session.into_stream().written())?;
+  info!("Replayed {} server lines successfully", recording.received().len());
+
+  Ok(())
+}
+
+/// Get the replay error a session failed with when the recorded server lines ran out. The
+/// replay stream is in memory, so no other I/O error carries an OS error code to keep.
+fn unwrap_mismatch(error: Box<dyn Error>) -> Box<dyn Error> {
+  let error = match error.downcast::<io::Error>() {
+    Ok(error) => *error,
+    Err(error) => return error,
+  };
+  let kind = error.kind();
+
+  match error.into_inner() {
+    Some(inner) if inner.is::<Err>() => inner,
+    Some(inner) => Box::new(io::Error::new(kind, inner)),
+    None => Box::new(io::Error::from(kind)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::transcript::{Event, Transcript};
+
+  const USERDATA: &str = r#"{"name": "Jane Doe", "emails": ["jane@example.com"], "birth_date": "01.02.1990",
+    "country": "Germany", "address": ["Street 1"]}"#;
+
+  /// Run a session against the given server lines, recording it to a transcript file named
+  /// after the test, and return the file name.
+  fn record(test: &str, attempts: &[&[&str]], redact: bool) -> std::path::PathBuf {
+    let filename = std::env::temp_dir().join(format!("exasol-{}-{}.jsonl", test, std::process::id()));
+    let mut transcript = Transcript::new(&filename, redact).unwrap();
+    let userdata: UserData = serde_json::from_str(USERDATA).unwrap();
+
+    for (attempt, lines) in attempts.iter().enumerate() {
+      transcript.record(&Event::Attempt { attempt: attempt as u32 + 1 }).unwrap();
+
+      let stream = ReplayStream::new(lines.iter().map(|line| line.to_string()));
+      let _ = Exasol::from_stream(stream, Endpoint::new("recorded", 443))
+        .with_transcript(transcript.clone())
+        .and_then(|session| session.handshake())
+        .and_then(|session| session.pow())
+        .and_then(|session| session.submit(&userdata));
+    }
+
+    filename
+  }
+
+  /// Replay a recorded transcript file with the recorded proof-of-work suffixes and remove it.
+  fn replay_file(filename: &Path) -> Res<()> {
+    let recording = Recording::new(filename);
+    std::fs::remove_file(filename).unwrap();
+    let recording = recording?;
+    replay(&recording, &serde_json::from_str(USERDATA)?, &FieldMap::builtin(), recording.solver())
+  }
+
+  /// Replay a recorded transcript file with the given solver and remove it.
+  fn replay_file_with(filename: &Path, solver: impl Solver + 'static) -> Res<()> {
+    let recording = Recording::new(filename);
+    std::fs::remove_file(filename).unwrap();
+    replay(&recording?, &serde_json::from_str(USERDATA)?, &FieldMap::builtin(), solver)
+  }
+
+  /// A solver that returns the same suffix whatever the authdata.
+  struct FixedSolver(&'static str);
+
+  impl Solver for FixedSolver {
+    fn solve(
+      &self,
+      _authdata: &str,
+      _difficulty: usize,
+      _cancel: &AtomicBool,
+      _progress: &(dyn Fn(usize, Duration) + Sync),
+    ) -> Res<Option<Vec<u8>>> {
+      Ok(Some(self.0.as_bytes().to_vec()))
+    }
+  }
+
+  const SESSION: &[&str] = &[
+    "HELO",
+    "POW authdata 2",
+    "NAME abc",
+    "MAILNUM def",
+    "MAIL1 ghi",
+    "END",
+  ];
+
+  #[test]
+  fn replays_recorded_session() {
+    replay_file(&record("session", &[SESSION], false)).unwrap();
+  }
+
+  #[test]
+  fn replays_redacted_session() {
+    replay_file(&record("redacted", &[SESSION], true)).unwrap();
+  }
+
+  #[test]
+  fn replays_last_attempt() {
+    let filename = record("attempts", &[&["HELO", "ERROR busy"], SESSION], false);
+    let recording = Recording::new(&filename).unwrap();
+    assert_eq!(recording.received(), SESSION);
+    assert_eq!(recording.endpoint(), &Endpoint::new("recorded", 443));
+    replay_file(&filename).unwrap();
+  }
+
+  #[test]
+  fn detects_different_response() {
+    let filename = record("different", &[SESSION], false);
+    let contents = std::fs::read_to_string(&filename).unwrap().replace("Jane Doe", "John Doe");
+    std::fs::write(&filename, contents).unwrap();
+
+    let error = replay_file(&filename).unwrap_err();
+    assert!(matches!(error.downcast_ref::<Err>(), Some(Err::ReplayMismatch(3, ..))), "{}", error);
+  }
+
+  #[test]
+  fn detects_invalid_pow() {
+    let filename = record("invalid-pow", &[SESSION], false);
+    let recording = Recording::new(&filename).unwrap();
+    std::fs::remove_file(&filename).unwrap();
+
+    let invalid = (0..)
+      .map(|n: u32| n.to_string())
+      .find(|suffix| !verify("authdata", suffix.as_bytes(), 2))
+      .unwrap();
+    let written = format!("EHLO\n{}\n", invalid);
+    let error = recording.check(written.as_bytes()).unwrap_err();
+    assert!(matches!(error.downcast_ref::<Err>(), Some(Err::ReplayInvalidPow(2, _))), "{}", error);
+  }
+
+  #[test]
+  fn replays_with_new_pow() {
+    replay_file_with(&record("solve", &[SESSION], false), crate::solver::ParallelSolver).unwrap();
+  }
+
+  #[test]
+  fn detects_invalid_new_pow() {
+    let invalid = (0..)
+      .map(|n: u32| n.to_string())
+      .find(|suffix| !verify("authdata", suffix.as_bytes(), 2))
+      .unwrap();
+    let solver = FixedSolver(Box::leak(invalid.into_boxed_str()));
+
+    let error = replay_file_with(&record("invalid-new-pow", &[SESSION], false), solver).unwrap_err();
+    assert!(matches!(error.downcast_ref::<Err>(), Some(Err::ReplayInvalidPow(2, _))), "{}", error);
+  }
+
+  #[test]
+  fn detects_missing_server_lines() {
+    let filename = record("missing", &[SESSION], false);
+    let contents = std::fs::read_to_string(&filename).unwrap();
+    let contents: Vec<&str> = contents.lines().filter(|line| !line.contains(r#""line":"END""#)).collect();
+    std::fs::write(&filename, contents.join("\n")).unwrap();
+
+    let error = replay_file(&filename).unwrap_err();
+    assert!(matches!(error.downcast_ref::<Err>(), Some(Err::ReplayMismatch(6, ..))), "{}", error);
+    assert!(error.to_string().contains("expected `OK`, got ``"), "{}", error);
+  }
+}
@@ -0,0 +1,365 @@
+#![warn(clippy::all)]
+
+use crate::cert_info::CertInfo;
+use crate::endpoint::{Endpoint, Endpoints};
+use crate::error::Err;
+use crate::identity::Identity;
+use crate::keylog::KeyLog;
+use crate::tls::{is_interruption, Pin, TlsConfig, TlsSession, TlsVersion, Verification, POLL_INTERVAL};
+use crate::Res;
+use log::{debug, error, info, warn};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::sign::{CertifiedKey, SingleCertAndKey};
+use rustls::{
+  version, CertificateError, ClientConfig, ClientConnection, DigitallySignedStruct, ProtocolVersion,
+  RootCertStore, SignatureScheme, StreamOwned, SupportedCipherSuite, SupportedProtocolVersion,
+};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::TcpStream;
+use std::sync::Arc;
+
+fn create_client_config(config: &TlsConfig, identity: &Identity, endpoint: &Endpoint) -> Res<ClientConfig> {
+  let provider = Arc::new(crypto_provider(config)?);
+  let verifier = ServerVerifier::new(config, identity, endpoint, &provider)?;
+
+  let mut certs = vec![CertificateDer::from(identity.cert().to_vec())];
+  for issuer in identity.chain() {
+    certs.push(CertificateDer::from(issuer.clone()));
+  }
+  let key = PrivateKeyDer::try_from(identity.key())?.clone_key();
+
+  // Not `with_client_auth_cert`, which refuses the X.509 v1 certificate of the challenge when
+  // checking that the key belongs to it (see `Identity::check_key` instead).
+  let certified_key = CertifiedKey::new(certs, provider.key_provider.load_private_key(key)?);
+
+  let mut client_config = ClientConfig::builder_with_provider(provider.clone())
+    .with_protocol_versions(&protocol_versions(config)?)?
+    .dangerous()
+    .with_custom_certificate_verifier(Arc::new(verifier))
+    .with_client_cert_resolver(Arc::new(SingleCertAndKey::from(certified_key)));
+
+  if let Some(keylog) = KeyLog::open(config, endpoint)? {
+    client_config.key_log = Arc::new(KeyLogWriter(keylog));
+  }
+
+  debug!("Created TLS client configuration");
+  Ok(client_config)
+}
+
+/// Get the TLS versions between the configured ones that rustls supports (1.2 and 1.3).
+fn protocol_versions(config: &TlsConfig) -> Res<Vec<&'static SupportedProtocolVersion>> {
+  let min = config.min_version().unwrap_or(TlsVersion::Tls1_2);
+  let max = config.max_version().unwrap_or(TlsVersion::Tls1_3);
+  let versions: Vec<_> = [
+    (TlsVersion::Tls1_2, &version::TLS12),
+    (TlsVersion::Tls1_3, &version::TLS13),
+  ]
+  .into_iter()
+  .filter(|(version, _)| (min..=max).contains(version))
+  .map(|(_, supported)| supported)
+  .collect();
+
+  if versions.is_empty() {
+    return Err::no_tls_version(&min.to_string(), &max.to_string());
+  }
+
+  debug!("TLS versions: {} to {}", min, max);
+  Ok(versions)
+}
+
+/// Get the ring provider, with only the configured cipher suites and key exchange groups.
+fn crypto_provider(config: &TlsConfig) -> Res<CryptoProvider> {
+  let mut provider = ring::default_provider();
+
+  if let Some(cipher_list) = config.cipher_list() {
+    debug!("Cipher list: {}", cipher_list);
+    let tls12 = select("cipher suite", cipher_list, &provider.cipher_suites, |suite| {
+      matches!(suite, SupportedCipherSuite::Tls12(_)).then(|| suite_name(suite))
+    })?;
+    provider
+      .cipher_suites
+      .retain(|suite| matches!(suite, SupportedCipherSuite::Tls13(_)) || tls12.contains(suite));
+  }
+
+  if let Some(ciphersuites) = config.ciphersuites() {
+    debug!("TLS 1.3 cipher suites: {}", ciphersuites);
+    let tls13 = select("TLS 1.3 cipher suite", ciphersuites, &provider.cipher_suites, |suite| {
+      matches!(suite, SupportedCipherSuite::Tls13(_)).then(|| suite_name(suite))
+    })?;
+    provider
+      .cipher_suites
+      .retain(|suite| matches!(suite, SupportedCipherSuite::Tls12(_)) || tls13.contains(suite));
+  }
+
+  if let Some(groups) = config.groups() {
+    debug!("Key exchange groups: {}", groups);
+    let groups =
+      select("key exchange group", groups, &provider.kx_groups, |group| Some(format!("{:?}", group.name())))?;
+    provider.kx_groups = groups;
+  }
+
+  Ok(provider)
+}
+
+/// Pick the items named in a colon-separated list, in its order, among the available ones.
+/// Items without a name (e.g. TLS 1.3 suites when picking TLS 1.2 ones) are left out.
+fn select<T: Copy>(
+  kind: &'static str,
+  list: &str,
+  available: &[T],
+  name: impl Fn(&T) -> Option<String>,
+) -> Res<Vec<T>> {
+  list
+    .split(':')
+    .map(|wanted| {
+      let canonical = canonical_name(wanted);
+      match available
+        .iter()
+        .find(|item| name(item).is_some_and(|name| name.eq_ignore_ascii_case(canonical)))
+      {
+        Some(item) => Ok(*item),
+        None => Err::unknown_tls_name(kind, wanted),
+      }
+    })
+    .collect()
+}
+
+/// Get the IANA name of a cipher suite, which rustls spells `TLS13_...` for TLS 1.3 suites.
+fn suite_name(suite: &SupportedCipherSuite) -> String {
+  let name = format!("{:?}", suite.suite());
+  match name.strip_prefix("TLS13_") {
+    Some(rest) => format!("TLS_{}", rest),
+    None => name,
+  }
+}
+
+/// Map the OpenSSL names of key exchange groups to those of rustls.
+fn canonical_name(name: &str) -> &str {
+  match name {
+    "P-256" | "prime256v1" => "secp256r1",
+    "P-384" => "secp384r1",
+    "P-521" => "secp521r1",
+    name => name,
+  }
+}
+
+/// Get the TLS version and cipher suite the handshake settled on.
+fn negotiated_params(conn: &ClientConnection) -> (String, String) {
+  let version = match conn.protocol_version() {
+    Some(ProtocolVersion::TLSv1_2) => TlsVersion::Tls1_2.to_string(),
+    Some(ProtocolVersion::TLSv1_3) => TlsVersion::Tls1_3.to_string(),
+    version => format!("{:?}", version),
+  };
+  let suite = conn
+    .negotiated_cipher_suite()
+    .map_or("none".to_string(), |suite| suite_name(&suite));
+  (version, suite)
+}
+
+/// Log the TLS version and cipher suite the handshake settled on.
+fn log_negotiated(conn: &ClientConnection) {
+  let (version, suite) = negotiated_params(conn);
+  info!("Negotiated {} with cipher suite {}", version, suite);
+}
+
+/// Writes the TLS secrets to the keylog file in the NSS key log format, like OpenSSL does.
+#[derive(Debug)]
+struct KeyLogWriter(KeyLog);
+
+impl rustls::KeyLog for KeyLogWriter {
+  fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+    self
+      .0
+      .write_line(&format!("{} {} {}", label, hex::encode(client_random), hex::encode(secret)));
+  }
+}
+
+/// How the server certificate is checked, see `Verification`.
+#[derive(Debug)]
+enum Check {
+  /// Verify the chain up to the trusted issuers or CAs.
+  WebPki(Arc<WebPkiServerVerifier>),
+  /// Accept only a certificate matching the pin.
+  Pin(Pin),
+  /// Accept any certificate.
+  Insecure,
+}
+
+/// Verifies the server certificate as configured, including its name when there is a server
+/// name to check, like with the OpenSSL backend.
+#[derive(Debug)]
+struct ServerVerifier {
+  check: Check,
+  // Whether the server certificate must be valid for the server name
+  check_name: bool,
+  provider: Arc<CryptoProvider>,
+}
+
+impl ServerVerifier {
+  fn new(
+    config: &TlsConfig,
+    identity: &Identity,
+    endpoint: &Endpoint,
+    provider: &Arc<CryptoProvider>,
+  ) -> Res<Self> {
+    debug!("Verifying the server certificate with: {}", config.verification());
+
+    let check = match config.verification() {
+      Verification::Chain => {
+        if identity.chain().is_empty() {
+          return Err::no_issuer_chain(config.cert_file());
+        }
+
+        let mut roots = RootCertStore::empty();
+        for issuer in identity.chain() {
+          debug!("Trusting issuer {}", CertInfo::new(issuer)?.subject());
+          roots.add(CertificateDer::from(issuer.clone()))?;
+        }
+
+        Check::WebPki(WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone()).build()?)
+      }
+      Verification::CaFile(ca_file) => {
+        let mut roots = RootCertStore::empty();
+        for ca in rustls_pemfile::certs(&mut BufReader::new(File::open(ca_file)?)) {
+          roots.add(ca?)?;
+        }
+
+        Check::WebPki(WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone()).build()?)
+      }
+      Verification::Pin(pin) => Check::Pin(pin.clone()),
+      Verification::Insecure => {
+        warn!("Server certificate verification is disabled");
+        Check::Insecure
+      }
+    };
+
+    let check_name = config.server_name_for(endpoint).is_some();
+    Ok(Self { check, check_name, provider: provider.clone() })
+  }
+}
+
+impl ServerCertVerifier for ServerVerifier {
+  fn verify_server_cert(
+    &self,
+    end_entity: &CertificateDer<'_>,
+    intermediates: &[CertificateDer<'_>],
+    server_name: &ServerName<'_>,
+    ocsp_response: &[u8],
+    now: UnixTime,
+  ) -> Result<ServerCertVerified, rustls::Error> {
+    match &self.check {
+      Check::WebPki(verifier) => {
+        match verifier.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now) {
+          // Like with OpenSSL, an endpoint given by IP address is only checked against a
+          // certificate verified with a CA file, unless a server name is configured.
+          Err(rustls::Error::InvalidCertificate(
+            CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. },
+          )) if !self.check_name => Ok(ServerCertVerified::assertion()),
+          res => res,
+        }
+      }
+      Check::Pin(pin) => match pin.matches(end_entity) {
+        Ok(true) => Ok(ServerCertVerified::assertion()),
+        Ok(false) => Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure)),
+        Err(e) => {
+          error!("Could not compute the server certificate fingerprint: {}", e);
+          Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure))
+        }
+      },
+      Check::Insecure => Ok(ServerCertVerified::assertion()),
+    }
+  }
+
+  fn verify_tls12_signature(
+    &self,
+    message: &[u8],
+    cert: &CertificateDer<'_>,
+    dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, rustls::Error> {
+    verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+  }
+
+  fn verify_tls13_signature(
+    &self,
+    message: &[u8],
+    cert: &CertificateDer<'_>,
+    dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, rustls::Error> {
+    verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+  }
+
+  fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+    self.provider.signature_verification_algorithms.supported_schemes()
+  }
+}
+
+/// Explain why the handshake failed if it was because of the server certificate.
+fn verification_error(e: &io::Error) -> Res<()> {
+  match e.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()) {
+    Some(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure)) => {
+      Err::server_verification("certificate does not match the pinned fingerprint")
+    }
+    Some(rustls::Error::InvalidCertificate(e)) => Err::server_verification(&e.to_string()),
+    _ => Ok(()),
+  }
+}
+
+impl TlsSession for StreamOwned<ClientConnection, TcpStream> {
+  fn connect(config: &TlsConfig, endpoints: &Endpoints) -> Res<(Self, Endpoint)> {
+    let identity = Identity::load_checked(config)?;
+    let (stream, endpoint) = endpoints.connect()?;
+    debug!("Connected TCP stream: {:?}", stream);
+    let client_config = create_client_config(config, &identity, &endpoint)?;
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    // rustls only sends DNS names in the SNI extension.
+    let server_name = config.server_name_for(&endpoint).unwrap_or_else(|| endpoint.host());
+    debug!("Server name: {}", server_name);
+    let server_name = ServerName::try_from(server_name)?;
+    let conn = ClientConnection::new(Arc::new(client_config), server_name)?;
+    debug!("Created TLS connection: {:?}", conn);
+    Ok((StreamOwned::new(conn, stream), endpoint))
+  }
+
+  fn handshake(&mut self) -> Res<bool> {
+    while self.conn.is_handshaking() {
+      match self.conn.complete_io(&mut self.sock) {
+        Ok(_) => {}
+        Err(e) if is_interruption(e.kind()) => return Ok(false),
+        Err(e) => {
+          verification_error(&e)?;
+          return Err(e.into());
+        }
+      }
+    }
+
+    log_negotiated(&self.conn);
+    Ok(true)
+  }
+
+  fn close_notify(&mut self) -> Res<()> {
+    if self.conn.is_handshaking() {
+      return Ok(());
+    }
+
+    self.conn.send_close_notify();
+    while self.conn.wants_write() {
+      self.conn.write_tls(&mut self.sock)?;
+    }
+
+    debug!("Sent TLS close_notify");
+    Ok(())
+  }
+
+  fn negotiated(&self) -> (String, String) {
+    negotiated_params(&self.conn)
+  }
+
+  fn peer_chain(&self) -> Res<Vec<Vec<u8>>> {
+    let chain = self.conn.peer_certificates().unwrap_or_default();
+    Ok(chain.iter().map(|cert| cert.to_vec()).collect())
+  }
+}
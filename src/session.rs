@@ -0,0 +1,705 @@
+#![warn(clippy::all)]
+
+use crate::cancel::CancelToken;
+use crate::endpoint::{Endpoint, Endpoints};
+use crate::error::Err;
+use crate::fields::{FieldMap, Report};
+use crate::observer::SessionObserver;
+use crate::protocol::{self, Exasol, Field, StateHelo};
+use crate::solver::Solver;
+use crate::tls::{TlsConfig, POLL_INTERVAL};
+use crate::transcript::{Event, Transcript};
+use crate::userdata::UserData;
+use crate::Res;
+use humantime::format_duration as humantime;
+use log::warn;
+use parking_lot::Mutex;
+use rand::Rng;
+use std::error::Error;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often and how fast a failed session is run again.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+  attempts: u32,
+  initial_backoff: Duration,
+  max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+  /// A single attempt, without retries.
+  fn default() -> Self {
+    Self::new(1, DEFAULT_INITIAL_BACKOFF, DEFAULT_MAX_BACKOFF)
+  }
+}
+
+/// Default delay before the first retry.
+pub const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Default upper bound of the delay between retries.
+pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+impl RetryPolicy {
+  /// Run a session up to `attempts` times in total, waiting `initial_backoff` before the
+  /// first retry and doubling it for every further retry, up to `max_backoff`.
+  pub fn new(attempts: u32, initial_backoff: Duration, max_backoff: Duration) -> Self {
+    Self { attempts: attempts.max(1), initial_backoff, max_backoff }
+  }
+
+  /// Get the maximum number of attempts.
+  pub fn attempts(&self) -> u32 {
+    self.attempts
+  }
+
+  /// Get the delay before the first retry.
+  pub fn initial_backoff(&self) -> Duration {
+    self.initial_backoff
+  }
+
+  /// Get the upper bound of the delay between retries.
+  pub fn max_backoff(&self) -> Duration {
+    self.max_backoff
+  }
+
+  /// Get the delay after the given failed attempt, with jitter: a random duration between
+  /// half and all of the exponential backoff.
+  fn backoff(&self, attempt: u32) -> Duration {
+    let factor = 2_u32.saturating_pow(attempt.saturating_sub(1));
+    let backoff = self.initial_backoff.saturating_mul(factor).min(self.max_backoff);
+    rand::thread_rng().gen_range(backoff / 2..=backoff)
+  }
+}
+
+/// Everything needed to run a session, apart from the user data.
+pub struct SessionConfig {
+  tls: TlsConfig,
+  endpoints: Endpoints,
+  fields: FieldMap,
+  transcript_file: Option<PathBuf>,
+  redact: bool,
+  retry: RetryPolicy,
+  read_timeout: Duration,
+  cancel: CancelToken,
+}
+
+impl SessionConfig {
+  pub fn new(tls: TlsConfig, endpoints: Endpoints) -> Self {
+    Self {
+      tls,
+      endpoints,
+      fields: FieldMap::builtin(),
+      transcript_file: None,
+      redact: false,
+      retry: RetryPolicy::default(),
+      read_timeout: protocol::DEFAULT_READ_TIMEOUT,
+      cancel: CancelToken::new(),
+    }
+  }
+
+  /// Answer the data commands of the server using the given field mapping.
+  pub fn with_fields(mut self, fields: FieldMap) -> Self {
+    self.fields = fields;
+    self
+  }
+
+  /// Record the session to the given transcript file, redacting personal data if `redact`
+  /// is set.
+  pub fn with_transcript_file(mut self, transcript_file: &Path, redact: bool) -> Self {
+    self.transcript_file = Some(transcript_file.to_path_buf());
+    self.redact = redact;
+    self
+  }
+
+  /// Run failed sessions again according to the given retry policy.
+  pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+    self.retry = retry;
+    self
+  }
+
+  /// Fail a session attempt when the server sends nothing for the given time while the
+  /// client waits for a line, or when the TLS handshake takes longer.
+  pub fn with_read_timeout(mut self, read_timeout: Duration) -> Self {
+    self.read_timeout = read_timeout;
+    self
+  }
+
+  /// Stop the session, including any retries, when the given token is cancelled.
+  pub fn with_cancel(mut self, cancel: CancelToken) -> Self {
+    self.cancel = cancel;
+    self
+  }
+
+  /// Get a reference to the TLS settings.
+  pub fn tls(&self) -> &TlsConfig {
+    &self.tls
+  }
+
+  /// Get a reference to the endpoints to connect to.
+  pub fn endpoints(&self) -> &Endpoints {
+    &self.endpoints
+  }
+
+  /// Get a reference to the field mapping.
+  pub fn fields(&self) -> &FieldMap {
+    &self.fields
+  }
+
+  /// Get a reference to the transcript file.
+  pub fn transcript_file(&self) -> Option<&Path> {
+    self.transcript_file.as_deref()
+  }
+
+  /// Get whether personal data is redacted from the transcript.
+  pub fn redact(&self) -> bool {
+    self.redact
+  }
+
+  /// Get a reference to the retry policy.
+  pub fn retry(&self) -> &RetryPolicy {
+    &self.retry
+  }
+
+  /// Get the time the server may stay silent while the client waits for a line.
+  pub fn read_timeout(&self) -> Duration {
+    self.read_timeout
+  }
+
+  /// Get a reference to the cancellation token.
+  pub fn cancel(&self) -> &CancelToken {
+    &self.cancel
+  }
+}
+
+/// The time spent in each phase of a session.
+#[derive(Clone, Debug, Default)]
+pub struct Timings {
+  connect: Duration,
+  handshake: Duration,
+  pow: Duration,
+  data: Duration,
+}
+
+impl Timings {
+  /// Get the time spent connecting, including failed attempts on other endpoints.
+  pub fn connect(&self) -> Duration {
+    self.connect
+  }
+
+  /// Get the time spent in the HELO handshake.
+  pub fn handshake(&self) -> Duration {
+    self.handshake
+  }
+
+  /// Get the time spent waiting for and solving the proof-of-work.
+  pub fn pow(&self) -> Duration {
+    self.pow
+  }
+
+  /// Get the time spent submitting the user data.
+  pub fn data(&self) -> Duration {
+    self.data
+  }
+
+  /// Get the total time of the session.
+  pub fn total(&self) -> Duration {
+    self.connect + self.handshake + self.pow + self.data
+  }
+}
+
+/// The proof-of-work of a session.
+#[derive(Clone, Debug, Default)]
+pub struct PowDetails {
+  authdata: String,
+  difficulty: usize,
+  suffix: String,
+  digest: String,
+}
+
+impl PowDetails {
+  /// Get a reference to the authdata sent by the server.
+  pub fn authdata(&self) -> &str {
+    &self.authdata
+  }
+
+  /// Get the difficulty sent by the server.
+  pub fn difficulty(&self) -> usize {
+    self.difficulty
+  }
+
+  /// Get a reference to the suffix found by the solver.
+  pub fn suffix(&self) -> &str {
+    &self.suffix
+  }
+
+  /// Get a reference to the hex-encoded digest of the authdata and suffix.
+  pub fn digest(&self) -> &str {
+    &self.digest
+  }
+}
+
+/// A user data field sent to the server.
+#[derive(Clone, Debug)]
+pub struct SubmittedField {
+  command: String,
+  name: String,
+  hash: String,
+}
+
+impl SubmittedField {
+  /// Get a reference to the server command the field answered.
+  pub fn command(&self) -> &str {
+    &self.command
+  }
+
+  /// Get a reference to the name of the user data field.
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  /// Get a reference to the hex-encoded hash sent along with the field.
+  pub fn hash(&self) -> &str {
+    &self.hash
+  }
+}
+
+/// The outcome of a successful session.
+#[derive(Clone, Debug)]
+pub struct SessionReport {
+  endpoint: Endpoint,
+  timings: Timings,
+  pow: PowDetails,
+  fields: Vec<SubmittedField>,
+  completeness: Report,
+}
+
+impl SessionReport {
+  /// Get a reference to the endpoint the session ran against.
+  pub fn endpoint(&self) -> &Endpoint {
+    &self.endpoint
+  }
+
+  /// Get a reference to the time spent in each phase.
+  pub fn timings(&self) -> &Timings {
+    &self.timings
+  }
+
+  /// Get a reference to the proof-of-work details.
+  pub fn pow(&self) -> &PowDetails {
+    &self.pow
+  }
+
+  /// Get a reference to the submitted fields, in order.
+  pub fn fields(&self) -> &[SubmittedField] {
+    &self.fields
+  }
+
+  /// Get a reference to the completeness report of the data phase.
+  pub fn completeness(&self) -> &Report {
+    &self.completeness
+  }
+}
+
+#[derive(Default)]
+struct Collected {
+  pow: PowDetails,
+  fields: Vec<SubmittedField>,
+  ended: bool,
+}
+
+/// Collects the proof-of-work and the submitted fields for the session report.
+struct Collector(Arc<Mutex<Collected>>);
+
+impl SessionObserver for Collector {
+  fn pow_started(&mut self, authdata: &str, difficulty: usize) {
+    let pow = &mut self.0.lock().pow;
+    pow.authdata = authdata.to_string();
+    pow.difficulty = difficulty;
+  }
+
+  fn pow_solved(&mut self, suffix: &str, digest: &str) {
+    let pow = &mut self.0.lock().pow;
+    pow.suffix = suffix.to_string();
+    pow.digest = digest.to_string();
+  }
+
+  fn field_submitted(&mut self, field: &Field) {
+    self.0.lock().fields.push(SubmittedField {
+      command: field.command().to_string(),
+      name: field.name().to_string(),
+      hash: field.hash(),
+    });
+  }
+
+  fn end_acknowledged(&mut self) {
+    self.0.lock().ended = true;
+  }
+}
+
+/// Passes the events of every attempt of a session to the same observer.
+struct Shared<O>(Arc<Mutex<O>>);
+
+impl<O: SessionObserver> SessionObserver for Shared<O> {
+  fn connected(&mut self, endpoint: &Endpoint) {
+    self.0.lock().connected(endpoint)
+  }
+
+  fn line_received(&mut self, line: &str) {
+    self.0.lock().line_received(line)
+  }
+
+  fn line_sent(&mut self, line: &str) {
+    self.0.lock().line_sent(line)
+  }
+
+  fn state_changed(&mut self, from: &'static str, to: &'static str) {
+    self.0.lock().state_changed(from, to)
+  }
+
+  fn pow_started(&mut self, authdata: &str, difficulty: usize) {
+    self.0.lock().pow_started(authdata, difficulty)
+  }
+
+  fn pow_progress(&mut self, iterations: usize, elapsed: Duration) {
+    self.0.lock().pow_progress(iterations, elapsed)
+  }
+
+  fn pow_solved(&mut self, suffix: &str, digest: &str) {
+    self.0.lock().pow_solved(suffix, digest)
+  }
+
+  fn field_submitted(&mut self, field: &Field) {
+    self.0.lock().field_submitted(field)
+  }
+
+  fn report(&mut self, report: &Report) {
+    self.0.lock().report(report)
+  }
+
+  fn error(&mut self, error: &dyn Error) {
+    self.0.lock().error(error)
+  }
+
+  fn end_acknowledged(&mut self) {
+    self.0.lock().end_acknowledged()
+  }
+
+  fn end(&mut self) {
+    self.0.lock().end()
+  }
+}
+
+/// Words in a server ERROR message that mean the submitted data was rejected, in which case
+/// running the session again would not help.
+const VALIDATION_WORDS: [&str; 4] = ["invalid", "validation", "incorrect", "wrong"];
+
+/// Get whether a session that failed with the given error may succeed when run again: the
+/// connection dropped, closed or timed out, the server stalled, no endpoint was reachable, or
+/// the server sent an ERROR that is not about validation. An empty line from the server is
+/// not retried, as the connection is still open then.
+pub fn is_retryable(e: &(dyn Error + 'static)) -> bool {
+  if let Some(e) = e.downcast_ref::<io::Error>() {
+    return is_retryable_io(e);
+  }
+
+  #[cfg(feature = "openssl")]
+  if let Some(e) = e.downcast_ref::<openssl::ssl::Error>() {
+    return e.io_error().is_some_and(is_retryable_io);
+  }
+
+  match e.downcast_ref::<Err>() {
+    Some(Err::NoReachableEndpoint(_)) | Some(Err::Timeout(..)) => true,
+    Some(Err::Server(_, msg)) => {
+      let msg = msg.to_lowercase();
+      !VALIDATION_WORDS.iter().any(|word| msg.contains(word))
+    }
+    _ => false,
+  }
+}
+
+fn is_retryable_io(e: &io::Error) -> bool {
+  matches!(
+    e.kind(),
+    io::ErrorKind::ConnectionReset
+      | io::ErrorKind::ConnectionAborted
+      | io::ErrorKind::ConnectionRefused
+      | io::ErrorKind::BrokenPipe
+      | io::ErrorKind::UnexpectedEof
+      | io::ErrorKind::TimedOut
+      | io::ErrorKind::WouldBlock
+  )
+}
+
+/// Runs the whole protocol from a single call.
+pub struct Session;
+
+impl Session {
+  /// Connect to the first reachable endpoint and set up the session up to the handshake,
+  /// e.g. to drive the remaining states by hand.
+  pub fn connect(
+    config: &SessionConfig,
+    solver: impl Solver + 'static,
+    observer: impl SessionObserver + 'static,
+  ) -> Res<Exasol<StateHelo>> {
+    Self::open(config, Self::transcript(config)?)?
+      .with_solver(solver)
+      .with_observer(observer)
+      .connect()
+  }
+
+  /// Run a full session: connect, handshake, solve the proof-of-work and submit the user
+  /// data. Sessions failing with a transient error are retried from the start according
+  /// to the retry policy of the configuration, but never once END has been acknowledged or
+  /// the session was cancelled.
+  pub fn run(
+    config: &SessionConfig,
+    userdata: &UserData,
+    solver: impl Solver + 'static,
+    observer: impl SessionObserver + 'static,
+  ) -> Res<SessionReport> {
+    let solver = Arc::new(solver);
+    let observer = Arc::new(Mutex::new(observer));
+    let mut transcript = Self::transcript(config)?;
+
+    Self::retry(config.retry(), config.cancel(), |attempt, collected| {
+      if let Some(transcript) = &mut transcript {
+        transcript.record(&Event::Attempt { attempt })?;
+      }
+
+      Self::run_once(
+        config,
+        userdata,
+        transcript.clone(),
+        Arc::clone(&solver),
+        Shared(Arc::clone(&observer)),
+        collected,
+      )
+    })
+  }
+
+  /// Run attempts until one succeeds or fails with an error that is not worth retrying: an
+  /// error that is not retryable, the last attempt failing, the session being cancelled, or
+  /// the attempt having acknowledged END.
+  fn retry<R>(
+    retry: &RetryPolicy,
+    cancel: &CancelToken,
+    mut run_once: impl FnMut(u32, &Arc<Mutex<Collected>>) -> Res<R>,
+  ) -> Res<R> {
+    let mut attempt = 1;
+
+    loop {
+      let collected = Arc::new(Mutex::new(Collected::default()));
+
+      let e = match run_once(attempt, &collected) {
+        Ok(res) => return Ok(res),
+        Err(e) => e,
+      };
+
+      if collected.lock().ended
+        || cancel.is_cancelled()
+        || attempt >= retry.attempts()
+        || !is_retryable(e.as_ref())
+      {
+        return Err(e);
+      }
+
+      let backoff = retry.backoff(attempt);
+      warn!(
+        "Session attempt {} of {} failed: {}; retrying in {}",
+        attempt,
+        retry.attempts(),
+        e,
+        humantime(backoff)
+      );
+
+      if !Self::sleep(backoff, cancel) {
+        return Err::cancelled();
+      }
+
+      attempt += 1;
+    }
+  }
+
+  fn run_once(
+    config: &SessionConfig,
+    userdata: &UserData,
+    transcript: Option<Transcript>,
+    solver: impl Solver + 'static,
+    observer: impl SessionObserver + 'static,
+    collected: &Arc<Mutex<Collected>>,
+  ) -> Res<SessionReport> {
+    let mut timings = Timings::default();
+
+    let start = Instant::now();
+    let session = Self::open(config, transcript)?
+      .with_solver(solver)
+      .with_observer(Collector(Arc::clone(collected)))
+      .with_observer(observer)
+      .connect()?;
+    timings.connect = start.elapsed();
+
+    let start = Instant::now();
+    let session = session.handshake()?;
+    timings.handshake = start.elapsed();
+
+    let start = Instant::now();
+    let session = session.pow()?;
+    timings.pow = start.elapsed();
+
+    let start = Instant::now();
+    let session = session.submit(userdata)?;
+    timings.data = start.elapsed();
+
+    let collected = std::mem::take(&mut *collected.lock());
+
+    Ok(SessionReport {
+      endpoint: session.endpoint().clone(),
+      timings,
+      pow: collected.pow,
+      fields: collected.fields,
+      completeness: session.report().clone(),
+    })
+  }
+
+  /// Sleep for the given duration, waking up early if the token is cancelled. Returns
+  /// whether the whole duration passed.
+  fn sleep(duration: Duration, cancel: &CancelToken) -> bool {
+    let end = Instant::now() + duration;
+
+    while !cancel.is_cancelled() {
+      let now = Instant::now();
+
+      if now >= end {
+        return true;
+      }
+
+      thread::sleep((end - now).min(POLL_INTERVAL));
+    }
+
+    false
+  }
+
+  /// Create the transcript file of the configuration, if any. It is created once per session,
+  /// so the attempts of a retried session are all kept in it.
+  fn transcript(config: &SessionConfig) -> Res<Option<Transcript>> {
+    config
+      .transcript_file()
+      .map(|transcript_file| Transcript::new(transcript_file, config.redact()))
+      .transpose()
+  }
+
+  fn open(config: &SessionConfig, transcript: Option<Transcript>) -> Res<Exasol> {
+    let mut session = Exasol::new(config.tls(), config.endpoints())?
+      .with_fields(config.fields().clone())
+      .with_read_timeout(config.read_timeout())
+      .with_cancel(config.cancel().clone());
+
+    if let Some(transcript) = transcript {
+      session = session.with_transcript(transcript)?;
+    }
+
+    Ok(session)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::endpoint::Order;
+  use crate::solver::ParallelSolver;
+  use std::net::TcpListener;
+
+  const CLIENT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/files/cert-and-key.pem");
+
+  #[test]
+  fn classifies_retryable_errors() {
+    let io_error = |kind: io::ErrorKind| -> Box<dyn Error> { Box::new(io::Error::from(kind)) };
+    let error = |e: Err| -> Box<dyn Error> { Box::new(e) };
+
+    for (e, retryable) in [
+      (io_error(io::ErrorKind::ConnectionReset), true),
+      (io_error(io::ErrorKind::UnexpectedEof), true),
+      (io_error(io::ErrorKind::TimedOut), true),
+      (io_error(io::ErrorKind::PermissionDenied), false),
+      (error(Err::Timeout("data from the server", String::from("1s"))), true),
+      (error(Err::NoReachableEndpoint(2)), true),
+      (error(Err::Server("DATA", String::from("server busy"))), true),
+      (error(Err::Server("DATA", String::from("Invalid birthdate"))), false),
+      (error(Err::Server("POW", String::from("wrong suffix"))), false),
+      (error(Err::CommandExpected), false),
+      (error(Err::ServerEnded("POW")), false),
+      (error(Err::Cancelled), false),
+      ("other".into(), false),
+    ] {
+      assert_eq!(is_retryable(e.as_ref()), retryable, "{}", e);
+    }
+  }
+
+  #[test]
+  fn backoff_doubles_with_jitter_up_to_the_maximum() {
+    let retry = RetryPolicy::new(10, Duration::from_secs(1), Duration::from_secs(5));
+
+    for (attempt, max) in [
+      (1, 1000),
+      (2, 2000),
+      (3, 4000),
+      (4, 5000),
+      (10, 5000),
+      (u32::MAX, 5000),
+    ] {
+      let max = Duration::from_millis(max);
+
+      for _ in 0..100 {
+        let backoff = retry.backoff(attempt);
+        assert!(backoff >= max / 2 && backoff <= max, "attempt {}: {:?}", attempt, backoff);
+      }
+    }
+  }
+
+  /// Count the attempts of a session whose every attempt fails with a retryable error,
+  /// after acknowledging END if `ended` is set.
+  fn attempts(retry: &RetryPolicy, ended: bool) -> u32 {
+    let mut attempts = 0;
+    let res: Res<()> = Session::retry(retry, &CancelToken::new(), |attempt, collected| {
+      attempts = attempt;
+      collected.lock().ended = ended;
+      Err(io::Error::from(io::ErrorKind::ConnectionReset).into())
+    });
+
+    assert!(res.is_err());
+    attempts
+  }
+
+  #[test]
+  fn retries_up_to_the_attempts() {
+    let retry = RetryPolicy::new(3, Duration::ZERO, Duration::ZERO);
+    assert_eq!(attempts(&retry, false), 3);
+    assert_eq!(attempts(&RetryPolicy::default(), false), 1);
+  }
+
+  #[test]
+  fn never_retries_after_end_was_acknowledged() {
+    let retry = RetryPolicy::new(3, Duration::ZERO, Duration::ZERO);
+    assert_eq!(attempts(&retry, true), 1);
+  }
+
+  #[test]
+  fn stalled_tls_handshake_times_out() {
+    // Connections are accepted by the kernel, but nothing answers the TLS handshake.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = Endpoint::new("127.0.0.1", listener.local_addr().unwrap().port());
+    let endpoints = Endpoints::new(vec![endpoint], Order::Sequential, Duration::from_secs(5));
+    let tls = TlsConfig::new(Path::new(CLIENT)).with_allow_expired(true);
+    let config = SessionConfig::new(tls, endpoints).with_read_timeout(Duration::from_millis(300));
+
+    let start = Instant::now();
+    let error = Session::connect(&config, ParallelSolver, ()).err().unwrap();
+
+    assert!(matches!(error.downcast_ref::<Err>(), Some(Err::Timeout(..))), "{}", error);
+    assert!(is_retryable(error.as_ref()));
+    assert!(start.elapsed() < Duration::from_secs(5));
+  }
+}
@@ -0,0 +1,49 @@
+#![warn(clippy::all)]
+
+use crate::Res;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A proof-of-work search for a suffix whose SHA1 digest, appended to the authdata, starts
+/// with `difficulty` zeros in hex.
+pub trait Solver: Send + Sync {
+  /// Search for a suffix matching the difficulty. Returns `None` if the search is cancelled
+  /// by setting `cancel`. Implementations should periodically pass the total number of
+  /// iterations so far and the elapsed time to `progress`.
+  fn solve(
+    &self,
+    authdata: &str,
+    difficulty: usize,
+    cancel: &AtomicBool,
+    progress: &(dyn Fn(usize, Duration) + Sync),
+  ) -> Res<Option<Vec<u8>>>;
+}
+
+impl<S: Solver + ?Sized> Solver for Arc<S> {
+  fn solve(
+    &self,
+    authdata: &str,
+    difficulty: usize,
+    cancel: &AtomicBool,
+    progress: &(dyn Fn(usize, Duration) + Sync),
+  ) -> Res<Option<Vec<u8>>> {
+    self.as_ref().solve(authdata, difficulty, cancel, progress)
+  }
+}
+
+/// The built-in solver, which searches on all threads of the rayon thread pool.
+#[derive(Default)]
+pub struct ParallelSolver;
+
+impl Solver for ParallelSolver {
+  fn solve(
+    &self,
+    authdata: &str,
+    difficulty: usize,
+    cancel: &AtomicBool,
+    progress: &(dyn Fn(usize, Duration) + Sync),
+  ) -> Res<Option<Vec<u8>>> {
+    crate::pow::pow(authdata, difficulty, cancel, progress)
+  }
+}
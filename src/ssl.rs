@@ -1,53 +1,44 @@
 #![warn(clippy::all)]
 
+use crate::endpoint::{Endpoint, Endpoints};
+use crate::error::Err;
+use crate::identity::Identity;
+use crate::keylog::KeyLog;
+use crate::tls::{is_interruption, Pin, TlsConfig, TlsSession, TlsVersion, Verification, POLL_INTERVAL};
 use crate::Res;
-use log::{debug, error};
+use log::{debug, error, info, warn};
 use openssl::pkey::PKey;
-use openssl::ssl::{Ssl, SslContext, SslContextBuilder, SslMethod, SslStream};
-use openssl::x509::X509;
-use std::fs::File;
-use std::io::{BufReader, Read, Write};
-use std::net::TcpStream;
-use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use openssl::ssl::{
+  ErrorCode, Ssl, SslContext, SslContextBuilder, SslMethod, SslRef, SslStream, SslVerifyMode, SslVersion,
+};
+use openssl::x509::verify::X509CheckFlags;
+use openssl::x509::{X509StoreContextRef, X509VerifyResult, X509};
+use std::net::{IpAddr, TcpStream};
 
-fn create_ssl_context(cert_file: &Path, keylog_file: Option<&Path>) -> Res<SslContext> {
-  debug!("Reading certificate chain and key from {}", cert_file.display());
-
-  let cert_file = File::open(cert_file)?;
-  let mut reader = BufReader::new(cert_file);
-  let mut contents = vec![];
-  let bytes = reader.read_to_end(&mut contents)?;
-  debug!("Read certificate chain and key file: {} bytes", bytes);
-
-  let pkey = PKey::private_key_from_pem(&contents)?;
-  debug!("Parsed private key: {:?}", pkey);
-
-  let cert = X509::from_pem(&contents)?;
-  debug!("Parsed certificate: {:?}", cert);
+pub(crate) fn create_ssl_context(
+  config: &TlsConfig,
+  identity: &Identity,
+  endpoint: &Endpoint,
+) -> Res<SslContext> {
+  let chain = identity
+    .chain()
+    .iter()
+    .map(|cert| X509::from_der(cert))
+    .collect::<Result<Vec<_>, _>>()?;
 
   let mut ctx_builder = SslContextBuilder::new(SslMethod::tls())?;
-  ctx_builder.set_private_key(&pkey)?;
-  ctx_builder.set_certificate(&cert)?;
+  ctx_builder.set_private_key(&*PKey::private_key_from_der(identity.key())?)?;
+  ctx_builder.set_certificate(&*X509::from_der(identity.cert())?)?;
+  for issuer in &chain {
+    ctx_builder.add_extra_chain_cert(issuer.clone())?;
+  }
   ctx_builder.check_private_key()?;
 
-  if let Some(keylog_file) = keylog_file {
-    let keylog_filename = PathBuf::from(keylog_file);
-    let keylog_file = Arc::new(Mutex::new(File::create(keylog_file)?));
-    debug!("Created keylog file `{}`", keylog_filename.display());
+  set_protocol(&mut ctx_builder, config)?;
+  set_verification(&mut ctx_builder, config, &chain)?;
 
-    ctx_builder.set_keylog_callback(move |_ssl, msg| match keylog_file.lock() {
-      Ok(mut keylog_file) => {
-        if let Err(e) = writeln!(keylog_file, "{}", msg) {
-          error!("Error writing to keylog file `{}`: {}", keylog_filename.display(), e);
-        }
-
-        if let Err(e) = keylog_file.flush() {
-          error!("Error flushing keylog file `{}`: {}", keylog_filename.display(), e);
-        }
-      }
-      Err(e) => error!("Error accessing keylog file `{}`: {}", keylog_filename.display(), e),
-    });
+  if let Some(keylog) = KeyLog::open(config, endpoint)? {
+    ctx_builder.set_keylog_callback(move |_ssl, line| keylog.write_line(line));
   }
 
   let ctx = ctx_builder.build();
@@ -55,16 +46,216 @@ fn create_ssl_context(cert_file: &Path, keylog_file: Option<&Path>) -> Res<SslCo
   Ok(ctx)
 }
 
-pub(crate) fn create_ssl_stream(
-  cert_file: &Path,
-  keylog_file: Option<&Path>,
-  address: &str,
-) -> Res<SslStream<TcpStream>> {
-  let ctx = create_ssl_context(cert_file, keylog_file)?;
-  let ssl = Ssl::new(&ctx)?;
-  let stream = TcpStream::connect(address)?;
-  debug!("Connected TCP stream: {:?}", stream);
-  let ssl_stream = SslStream::new(ssl, stream)?;
-  debug!("Created SSL stream: {:?}", ssl_stream);
-  Ok(ssl_stream)
+/// Create the TLS session for the endpoint, with its server name sent (SNI) and checked
+/// against the server certificate unless the certificate is pinned or not verified.
+pub(crate) fn create_ssl(ctx: &SslContext, config: &TlsConfig, endpoint: &Endpoint) -> Res<Ssl> {
+  let mut ssl = Ssl::new(ctx)?;
+
+  let server_name = match config.server_name_for(endpoint) {
+    Some(server_name) => server_name,
+    None => {
+      debug!("No server name to send or verify for {}", endpoint);
+      return Ok(ssl);
+    }
+  };
+
+  // SNI only carries DNS names.
+  let ip = server_name.parse::<IpAddr>().ok();
+  if ip.is_none() {
+    ssl.set_hostname(&server_name)?;
+    debug!("Sending server name {}", server_name);
+  }
+
+  if matches!(config.verification(), Verification::Chain | Verification::CaFile(_)) {
+    let param = ssl.param_mut();
+    param.set_hostflags(X509CheckFlags::NO_PARTIAL_WILDCARDS);
+    match ip {
+      Some(ip) => param.set_ip(ip)?,
+      None => param.set_host(&server_name)?,
+    }
+    debug!("Verifying the server certificate against {}", server_name);
+  }
+
+  Ok(ssl)
+}
+
+/// Restrict the TLS versions, cipher suites and key exchange groups as configured.
+fn set_protocol(ctx_builder: &mut SslContextBuilder, config: &TlsConfig) -> Res<()> {
+  if let (Some(min), Some(max)) = (config.min_version(), config.max_version()) {
+    if min > max {
+      return Err::no_tls_version(&min.to_string(), &max.to_string());
+    }
+  }
+
+  ctx_builder.set_min_proto_version(config.min_version().map(ssl_version))?;
+  ctx_builder.set_max_proto_version(config.max_version().map(ssl_version))?;
+  debug!("TLS versions: {:?} to {:?}", config.min_version(), config.max_version());
+
+  if let Some(cipher_list) = config.cipher_list() {
+    debug!("Cipher list: {}", cipher_list);
+    if ctx_builder.set_cipher_list(cipher_list).is_err() {
+      return Err::unknown_tls_name("cipher list", cipher_list);
+    }
+  }
+
+  if let Some(ciphersuites) = config.ciphersuites() {
+    debug!("TLS 1.3 cipher suites: {}", ciphersuites);
+    if ctx_builder.set_ciphersuites(ciphersuites).is_err() {
+      return Err::unknown_tls_name("TLS 1.3 cipher suite list", ciphersuites);
+    }
+  }
+
+  if let Some(groups) = config.groups() {
+    debug!("Key exchange groups: {}", groups);
+    if ctx_builder.set_groups_list(groups).is_err() {
+      return Err::unknown_tls_name("key exchange group list", groups);
+    }
+  }
+
+  Ok(())
+}
+
+fn ssl_version(version: TlsVersion) -> SslVersion {
+  match version {
+    TlsVersion::Tls1_0 => SslVersion::TLS1,
+    TlsVersion::Tls1_1 => SslVersion::TLS1_1,
+    TlsVersion::Tls1_2 => SslVersion::TLS1_2,
+    TlsVersion::Tls1_3 => SslVersion::TLS1_3,
+  }
+}
+
+/// Get the TLS version and cipher suite the handshake settled on.
+fn negotiated_params(ssl: &SslRef) -> (String, String) {
+  let cipher = ssl.current_cipher().map_or("none", |cipher| cipher.name());
+  (ssl.version_str().to_string(), cipher.to_string())
+}
+
+/// Log the TLS version and cipher suite the handshake settled on.
+pub(crate) fn log_negotiated(ssl: &SslRef) {
+  let (version, cipher) = negotiated_params(ssl);
+  info!("Negotiated {} with cipher suite {}", version, cipher);
+}
+
+/// Set up the verification of the server certificate.
+fn set_verification(ctx_builder: &mut SslContextBuilder, config: &TlsConfig, chain: &[X509]) -> Res<()> {
+  debug!("Verifying the server certificate with: {}", config.verification());
+
+  match config.verification() {
+    Verification::Chain => {
+      if chain.is_empty() {
+        return Err::no_issuer_chain(config.cert_file());
+      }
+
+      for issuer in chain {
+        debug!("Trusting issuer {:?}", issuer.subject_name());
+        ctx_builder.cert_store_mut().add_cert(issuer.clone())?;
+      }
+
+      ctx_builder.set_verify(SslVerifyMode::PEER);
+    }
+    Verification::CaFile(ca_file) => {
+      ctx_builder.set_ca_file(ca_file)?;
+      ctx_builder.set_verify(SslVerifyMode::PEER);
+    }
+    Verification::Pin(pin) => {
+      let pin = pin.clone();
+      ctx_builder.set_verify_callback(SslVerifyMode::PEER, move |_preverified, ctx| verify_pin(&pin, ctx));
+    }
+    Verification::Insecure => {
+      warn!("Server certificate verification is disabled");
+      ctx_builder.set_verify(SslVerifyMode::NONE);
+    }
+  }
+
+  Ok(())
+}
+
+/// Accept the server certificate only if it matches the pin, ignoring any other failure
+/// (e.g. a self-signed certificate) along the chain.
+fn verify_pin(pin: &Pin, ctx: &mut X509StoreContextRef) -> bool {
+  if ctx.error_depth() != 0 {
+    return true;
+  }
+
+  let matches = match ctx
+    .current_cert()
+    .map(|cert| cert.to_der().map_err(Into::into).and_then(|der| pin.matches(&der)))
+  {
+    Some(Ok(matches)) => matches,
+    Some(Err(e)) => {
+      error!("Could not compute the server certificate fingerprint: {}", e);
+      false
+    }
+    None => false,
+  };
+
+  if matches {
+    ctx.set_error(X509VerifyResult::OK);
+  } else {
+    ctx.set_error(X509VerifyResult::APPLICATION_VERIFICATION);
+  }
+
+  matches
+}
+
+/// Explain why the handshake failed if it was because of the server certificate.
+pub(crate) fn verification_error(ssl: &SslRef) -> Res<()> {
+  match ssl.verify_result() {
+    X509VerifyResult::OK => Ok(()),
+    X509VerifyResult::APPLICATION_VERIFICATION => {
+      Err::server_verification("certificate does not match the pinned fingerprint")
+    }
+    result => Err::server_verification(result.error_string()),
+  }
+}
+
+impl TlsSession for SslStream<TcpStream> {
+  fn connect(config: &TlsConfig, endpoints: &Endpoints) -> Res<(Self, Endpoint)> {
+    let identity = Identity::load_checked(config)?;
+    let (stream, endpoint) = endpoints.connect()?;
+    debug!("Connected TCP stream: {:?}", stream);
+    let ctx = create_ssl_context(config, &identity, &endpoint)?;
+    let ssl = create_ssl(&ctx, config, &endpoint)?;
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    let ssl_stream = SslStream::new(ssl, stream)?;
+    debug!("Created SSL stream: {:?}", ssl_stream);
+    Ok((ssl_stream, endpoint))
+  }
+
+  fn handshake(&mut self) -> Res<bool> {
+    match SslStream::connect(self) {
+      Ok(()) => {
+        log_negotiated(self.ssl());
+        Ok(true)
+      }
+      Err(e)
+        if e.code() == ErrorCode::WANT_READ || e.io_error().is_some_and(|e| is_interruption(e.kind())) =>
+      {
+        Ok(false)
+      }
+      Err(e) => {
+        verification_error(self.ssl())?;
+        Err(e.into())
+      }
+    }
+  }
+
+  fn close_notify(&mut self) -> Res<()> {
+    if !self.ssl().is_init_finished() {
+      return Ok(());
+    }
+
+    self.shutdown()?;
+    debug!("Sent TLS close_notify");
+    Ok(())
+  }
+
+  fn negotiated(&self) -> (String, String) {
+    negotiated_params(self.ssl())
+  }
+
+  fn peer_chain(&self) -> Res<Vec<Vec<u8>>> {
+    let chain = self.ssl().peer_cert_chain();
+    Ok(chain.map_or_else(|| Ok(Vec::new()), |chain| chain.iter().map(|cert| cert.to_der()).collect())?)
+  }
 }
@@ -0,0 +1,547 @@
+#![warn(clippy::all)]
+
+use crate::endpoint::{Endpoint, Endpoints};
+use crate::error::Err;
+pub use crate::identity::restore_terminal;
+use crate::identity::PassphraseCache;
+use crate::keylog::KeyLogFiles;
+use crate::x509;
+use crate::Res;
+use log::debug;
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How long a read from the server blocks before the session checks whether it was
+/// cancelled.
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The TLS stream sessions run over, depending on the TLS backend the crate is built with.
+#[cfg(not(feature = "rustls"))]
+pub type TlsStream = openssl::ssl::SslStream<TcpStream>;
+
+/// The TLS stream sessions run over, depending on the TLS backend the crate is built with.
+#[cfg(feature = "rustls")]
+pub type TlsStream = rustls::StreamOwned<rustls::ClientConnection, TcpStream>;
+
+/// What the protocol needs from a TLS backend besides reading and writing.
+pub(crate) trait TlsSession: Read + Write + Sized {
+  /// Connect to the first reachable endpoint and set up the TLS session, without running the
+  /// handshake yet.
+  fn connect(config: &TlsConfig, endpoints: &Endpoints) -> Res<(Self, Endpoint)>;
+
+  /// Run the TLS handshake. Returns false if a read timed out before it completed, in which
+  /// case it is resumed by calling this again.
+  fn handshake(&mut self) -> Res<bool>;
+
+  /// Send a TLS close_notify alert to the server, without waiting for its own. Nothing is
+  /// sent if the TLS handshake did not complete.
+  fn close_notify(&mut self) -> Res<()>;
+
+  /// Get the TLS version and cipher suite the handshake settled on.
+  fn negotiated(&self) -> (String, String);
+
+  /// Get the DER-encoded certificate chain the server sent, starting with its own certificate.
+  fn peer_chain(&self) -> Res<Vec<Vec<u8>>>;
+}
+
+/// Get whether a read stopped because of the socket timeout or a signal, and may be resumed.
+pub(crate) fn is_interruption(kind: io::ErrorKind) -> bool {
+  matches!(kind, io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted)
+}
+
+/// A SHA-256 fingerprint the server certificate must match.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Pin {
+  /// Fingerprint of the DER-encoded SubjectPublicKeyInfo, which survives certificate renewals
+  /// that keep the key.
+  Spki([u8; 32]),
+  /// Fingerprint of the whole DER-encoded certificate.
+  Cert([u8; 32]),
+}
+
+impl Pin {
+  /// Get whether the DER-encoded certificate matches the pin.
+  pub(crate) fn matches(&self, cert: &[u8]) -> Res<bool> {
+    let (expected, digest) = match self {
+      Pin::Spki(expected) => (expected, x509::sha256(&x509::public_key(cert)?)),
+      Pin::Cert(expected) => (expected, x509::sha256(cert)),
+    };
+
+    debug!("Server certificate fingerprint: {}", hex::encode(digest));
+    Ok(expected == &digest)
+  }
+}
+
+impl fmt::Display for Pin {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Pin::Spki(digest) => write!(f, "spki:{}", hex::encode(digest)),
+      Pin::Cert(digest) => write!(f, "cert:{}", hex::encode(digest)),
+    }
+  }
+}
+
+impl FromStr for Pin {
+  type Err = Err;
+
+  /// Parse `spki:HEX` or `cert:HEX`, where the hex digits may be separated by colons as
+  /// printed by `openssl x509 -fingerprint -sha256`.
+  fn from_str(pin: &str) -> Result<Self, Self::Err> {
+    let invalid = |reason| Err::InvalidPin(pin.to_string(), reason);
+
+    let (kind, digest) = pin
+      .split_once(':')
+      .ok_or_else(|| invalid("expecting `spki:HEX` or `cert:HEX`"))?;
+    let make: fn([u8; 32]) -> Pin = match kind {
+      "spki" => Pin::Spki,
+      "cert" => Pin::Cert,
+      _ => return Err(invalid("expecting `spki:HEX` or `cert:HEX`")),
+    };
+    let digest = hex::decode(digest.replace(':', "")).map_err(|_| invalid("invalid hex digits"))?;
+    let digest = <[u8; 32]>::try_from(digest).map_err(|_| invalid("expecting a SHA-256 digest"))?;
+
+    Ok(make(digest))
+  }
+}
+
+impl<'de> Deserialize<'de> for Pin {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: Deserializer<'de>,
+  {
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+  }
+}
+
+/// How the certificate of the server is verified.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Verification {
+  /// Trust the issuer certificates following the client certificate in the certificate or
+  /// chain file (e.g. `exatest.dynu.net` for the challenge server).
+  #[default]
+  Chain,
+  /// Trust the CA certificates in the given PEM bundle.
+  CaFile(PathBuf),
+  /// Accept only a server certificate matching the fingerprint, whoever issued it.
+  Pin(Pin),
+  /// Accept any server certificate. Only meant for testing.
+  Insecure,
+}
+
+impl fmt::Display for Verification {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Verification::Chain => write!(f, "issuer chain of the client certificate"),
+      Verification::CaFile(ca_file) => write!(f, "CA file {}", ca_file.display()),
+      Verification::Pin(pin) => write!(f, "pinned fingerprint {}", pin),
+      Verification::Insecure => write!(f, "none (insecure)"),
+    }
+  }
+}
+
+/// A TLS protocol version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+  Tls1_0,
+  Tls1_1,
+  Tls1_2,
+  Tls1_3,
+}
+
+impl fmt::Display for TlsVersion {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      TlsVersion::Tls1_0 => write!(f, "TLSv1"),
+      TlsVersion::Tls1_1 => write!(f, "TLSv1.1"),
+      TlsVersion::Tls1_2 => write!(f, "TLSv1.2"),
+      TlsVersion::Tls1_3 => write!(f, "TLSv1.3"),
+    }
+  }
+}
+
+impl FromStr for TlsVersion {
+  type Err = Err;
+
+  /// Parse `1.2`, also accepting the `TLSv1.2` spelling used by OpenSSL.
+  fn from_str(version: &str) -> Result<Self, Self::Err> {
+    let number = version.strip_prefix("TLSv").unwrap_or(version);
+
+    match number {
+      "1" | "1.0" => Ok(TlsVersion::Tls1_0),
+      "1.1" => Ok(TlsVersion::Tls1_1),
+      "1.2" => Ok(TlsVersion::Tls1_2),
+      "1.3" => Ok(TlsVersion::Tls1_3),
+      _ => Err(Err::InvalidTlsVersion(version.to_string())),
+    }
+  }
+}
+
+impl<'de> Deserialize<'de> for TlsVersion {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: Deserializer<'de>,
+  {
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+  }
+}
+
+/// Where the passphrase of an encrypted private key or PKCS#12 bundle comes from.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Passphrase {
+  /// Ask on the terminal.
+  #[default]
+  Prompt,
+  /// Read it from the given environment variable.
+  Env(String),
+  /// Read the first line of the given file.
+  File(PathBuf),
+}
+
+/// Settings of the TLS connection to the server.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+  cert_file: PathBuf,
+  key_file: Option<PathBuf>,
+  key_env: Option<String>,
+  passphrase: Passphrase,
+  passphrase_cache: PassphraseCache,
+  chain_file: Option<PathBuf>,
+  allow_expired: bool,
+  keylog_file: Option<PathBuf>,
+  keylog_append: bool,
+  keylog_files: KeyLogFiles,
+  verification: Verification,
+  min_version: Option<TlsVersion>,
+  max_version: Option<TlsVersion>,
+  cipher_list: Option<String>,
+  ciphersuites: Option<String>,
+  groups: Option<String>,
+  server_name: Option<String>,
+}
+
+impl TlsConfig {
+  /// Use the client certificate in the given PEM, DER or PKCS#12 file. The file may also
+  /// hold the private key and the issuer chain.
+  pub fn new(cert_file: &Path) -> Self {
+    Self {
+      cert_file: cert_file.to_path_buf(),
+      key_file: None,
+      key_env: None,
+      passphrase: Passphrase::default(),
+      passphrase_cache: PassphraseCache::default(),
+      chain_file: None,
+      allow_expired: false,
+      keylog_file: None,
+      keylog_append: false,
+      keylog_files: KeyLogFiles::default(),
+      verification: Verification::default(),
+      min_version: None,
+      max_version: None,
+      cipher_list: None,
+      ciphersuites: None,
+      groups: None,
+      server_name: None,
+    }
+  }
+
+  /// Read the private key from the given PEM or DER file instead of the certificate file.
+  pub fn with_key_file(mut self, key_file: &Path) -> Self {
+    self.key_file = Some(key_file.to_path_buf());
+    self
+  }
+
+  /// Read the private key in PEM format from the given environment variable instead of a
+  /// file, e.g. in CI jobs.
+  pub fn with_key_env(mut self, var: &str) -> Self {
+    self.key_env = Some(var.to_string());
+    self
+  }
+
+  /// Get the passphrase of encrypted keys and bundles from the given source instead of
+  /// prompting for it.
+  pub fn with_passphrase(mut self, passphrase: Passphrase) -> Self {
+    self.passphrase = passphrase;
+    self
+  }
+
+  /// Read further issuer certificates from the given PEM, DER or PKCS#12 file, after those
+  /// in the certificate file.
+  pub fn with_chain_file(mut self, chain_file: &Path) -> Self {
+    self.chain_file = Some(chain_file.to_path_buf());
+    self
+  }
+
+  /// Connect even if the client certificate is expired or not yet valid, which is
+  /// otherwise refused before connecting.
+  pub fn with_allow_expired(mut self, allow_expired: bool) -> Self {
+    self.allow_expired = allow_expired;
+    self
+  }
+
+  /// Write the TLS secrets to the given keylog file (e.g. for use with Wireshark) instead
+  /// of the one named by the `SSLKEYLOGFILE` environment variable. `{timestamp}` and
+  /// `{endpoint}` in the file name are replaced for each session.
+  pub fn with_keylog_file(mut self, keylog_file: &Path) -> Self {
+    self.keylog_file = Some(keylog_file.to_path_buf());
+    self
+  }
+
+  /// Append to the keylog file instead of truncating it, to keep the secrets of earlier
+  /// sessions.
+  pub fn with_keylog_append(mut self, keylog_append: bool) -> Self {
+    self.keylog_append = keylog_append;
+    self
+  }
+
+  /// Verify the certificate of the server as given instead of against the issuer chain of
+  /// the client certificate.
+  pub fn with_verification(mut self, verification: Verification) -> Self {
+    self.verification = verification;
+    self
+  }
+
+  /// Negotiate at least the given TLS version.
+  pub fn with_min_version(mut self, version: TlsVersion) -> Self {
+    self.min_version = Some(version);
+    self
+  }
+
+  /// Negotiate at most the given TLS version.
+  pub fn with_max_version(mut self, version: TlsVersion) -> Self {
+    self.max_version = Some(version);
+    self
+  }
+
+  /// Offer only the given cipher suites for TLS 1.2 and older, as a colon-separated list in
+  /// OpenSSL syntax (e.g. `ECDHE-ECDSA-AES256-GCM-SHA384`). With rustls, the IANA names
+  /// (e.g. `TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384`) are used instead.
+  pub fn with_cipher_list(mut self, cipher_list: &str) -> Self {
+    self.cipher_list = Some(cipher_list.to_string());
+    self
+  }
+
+  /// Offer only the given TLS 1.3 cipher suites, as a colon-separated list (e.g.
+  /// `TLS_AES_256_GCM_SHA384:TLS_CHACHA20_POLY1305_SHA256`).
+  pub fn with_ciphersuites(mut self, ciphersuites: &str) -> Self {
+    self.ciphersuites = Some(ciphersuites.to_string());
+    self
+  }
+
+  /// Offer only the given key exchange groups, as a colon-separated list (e.g.
+  /// `X25519:P-256`).
+  pub fn with_groups(mut self, groups: &str) -> Self {
+    self.groups = Some(groups.to_string());
+    self
+  }
+
+  /// Send the given server name (SNI) and verify the server certificate against it, instead
+  /// of the host of the endpoint.
+  pub fn with_server_name(mut self, server_name: &str) -> Self {
+    self.server_name = Some(server_name.to_string());
+    self
+  }
+
+  /// Get a reference to the certificate file.
+  pub fn cert_file(&self) -> &Path {
+    &self.cert_file
+  }
+
+  /// Get a reference to the private key file.
+  pub fn key_file(&self) -> Option<&Path> {
+    self.key_file.as_deref()
+  }
+
+  /// Get a reference to the environment variable holding the private key.
+  pub fn key_env(&self) -> Option<&str> {
+    self.key_env.as_deref()
+  }
+
+  /// Get a reference to the source of the passphrase.
+  pub fn passphrase(&self) -> &Passphrase {
+    &self.passphrase
+  }
+
+  /// Get a reference to the passphrase resolved so far, shared with the clones of this
+  /// configuration.
+  pub(crate) fn passphrase_cache(&self) -> &PassphraseCache {
+    &self.passphrase_cache
+  }
+
+  /// Get a reference to the issuer chain file.
+  pub fn chain_file(&self) -> Option<&Path> {
+    self.chain_file.as_deref()
+  }
+
+  /// Get whether an expired or not yet valid client certificate is used anyway.
+  pub fn allow_expired(&self) -> bool {
+    self.allow_expired
+  }
+
+  /// Get a reference to the keylog file.
+  pub fn keylog_file(&self) -> Option<&Path> {
+    self.keylog_file.as_deref()
+  }
+
+  /// Get whether the keylog file is appended to.
+  pub fn keylog_append(&self) -> bool {
+    self.keylog_append
+  }
+
+  /// Get a reference to the keylog files opened so far, shared with the clones of this
+  /// configuration.
+  pub(crate) fn keylog_files(&self) -> &KeyLogFiles {
+    &self.keylog_files
+  }
+
+  /// Get a reference to how the server certificate is verified.
+  pub fn verification(&self) -> &Verification {
+    &self.verification
+  }
+
+  /// Get the lowest TLS version to negotiate.
+  pub fn min_version(&self) -> Option<TlsVersion> {
+    self.min_version
+  }
+
+  /// Get the highest TLS version to negotiate.
+  pub fn max_version(&self) -> Option<TlsVersion> {
+    self.max_version
+  }
+
+  /// Get a reference to the cipher suites offered for TLS 1.2 and older.
+  pub fn cipher_list(&self) -> Option<&str> {
+    self.cipher_list.as_deref()
+  }
+
+  /// Get a reference to the TLS 1.3 cipher suites offered.
+  pub fn ciphersuites(&self) -> Option<&str> {
+    self.ciphersuites.as_deref()
+  }
+
+  /// Get a reference to the key exchange groups offered.
+  pub fn groups(&self) -> Option<&str> {
+    self.groups.as_deref()
+  }
+
+  /// Get a reference to the configured server name.
+  pub fn server_name(&self) -> Option<&str> {
+    self.server_name.as_deref()
+  }
+
+  /// Get the name to send (SNI) and to verify the server certificate against for the given
+  /// endpoint: the configured server name, or else the host of the endpoint. An IP address is
+  /// only checked against the certificate of a CA file, as the one of the challenge server,
+  /// reached by IP address, only names its host.
+  pub(crate) fn server_name_for(&self, endpoint: &Endpoint) -> Option<String> {
+    match (&self.server_name, endpoint) {
+      (Some(server_name), _) => Some(server_name.clone()),
+      (None, Endpoint::Host(host, _)) if host.parse::<IpAddr>().is_err() => Some(host.clone()),
+      (None, _) if matches!(self.verification, Verification::CaFile(_)) => Some(endpoint.host()),
+      (None, _) => None,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::endpoint::Order;
+  use std::net::TcpListener;
+
+  const CLIENT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/files/cert-and-key.pem");
+  const CA: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/files/test-ca.pem");
+  // Valid for localhost and 127.0.0.2 only
+  const SERVER: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/files/test-server.pem");
+
+  /// Accept TLS sessions with the test server certificate on a local port and return it.
+  #[cfg(not(feature = "rustls"))]
+  fn serve() -> u16 {
+    use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+    acceptor.set_private_key_file(SERVER, SslFiletype::PEM).unwrap();
+    acceptor.set_certificate_chain_file(SERVER).unwrap();
+    let acceptor = acceptor.build();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+      for stream in listener.incoming() {
+        let _ = acceptor.accept(stream.unwrap());
+      }
+    });
+
+    port
+  }
+
+  /// Accept TLS sessions with the test server certificate on a local port and return it.
+  #[cfg(feature = "rustls")]
+  fn serve() -> u16 {
+    use rustls::{ServerConfig, ServerConnection};
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::sync::Arc;
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(SERVER).unwrap()))
+      .collect::<Result<_, _>>()
+      .unwrap();
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(SERVER).unwrap()))
+      .unwrap()
+      .unwrap();
+    let config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+      .with_safe_default_protocol_versions()
+      .unwrap()
+      .with_no_client_auth()
+      .with_single_cert(certs, key)
+      .unwrap();
+    let config = Arc::new(config);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+      for stream in listener.incoming() {
+        let mut stream = stream.unwrap();
+        let mut conn = ServerConnection::new(config.clone()).unwrap();
+        while conn.is_handshaking() && conn.complete_io(&mut stream).is_ok() {}
+      }
+    });
+
+    port
+  }
+
+  /// Run a TLS handshake with the test server at 127.0.0.1, verified with the test CA.
+  fn handshake(port: u16, server_name: Option<&str>) -> Res<()> {
+    let endpoints =
+      Endpoints::new(vec![Endpoint::new("127.0.0.1", port)], Order::Sequential, Duration::from_secs(5));
+    let mut config = TlsConfig::new(Path::new(CLIENT))
+      .with_allow_expired(true)
+      .with_verification(Verification::CaFile(CA.into()));
+    if let Some(server_name) = server_name {
+      config = config.with_server_name(server_name);
+    }
+
+    let (mut stream, _) = <TlsStream as TlsSession>::connect(&config, &endpoints)?;
+    while !stream.handshake()? {}
+    Ok(())
+  }
+
+  #[test]
+  fn checks_server_certificate_names() {
+    let port = serve();
+
+    handshake(port, Some("localhost")).unwrap();
+    handshake(port, Some("127.0.0.2")).unwrap();
+
+    for server_name in [None, Some("other.example"), Some("127.0.0.3")] {
+      let error = handshake(port, server_name).unwrap_err();
+      assert!(matches!(error.downcast_ref::<Err>(), Some(Err::ServerVerification(_))), "{}", error);
+    }
+  }
+}
@@ -0,0 +1,75 @@
+#![warn(clippy::all)]
+
+use crate::Res;
+use chrono::{DateTime, Utc};
+use log::debug;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Placeholder written instead of personal data when the transcript is redacted.
+pub const REDACTED: &str = "<redacted>";
+
+/// An event of a protocol session as it is recorded in a transcript.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+  /// A new attempt of the session started; the events up to the next attempt belong to it.
+  Attempt { attempt: u32 },
+  /// The connection to the server was established.
+  Connected { endpoint: &'a str },
+  /// A line received from the server, without the trailing newline.
+  Received { line: &'a str },
+  /// A line sent to the server, without the trailing newline.
+  Sent { line: &'a str },
+  /// The session moved to a new state.
+  State { state: &'a str },
+  /// The proof-of-work was solved.
+  Pow { authdata: &'a str, difficulty: usize, suffix: &'a str, digest: &'a str },
+  /// A data command was answered.
+  Response { command: &'a str, hash: &'a str, datum: Option<&'a str> },
+  /// The server sent END; lists the required data commands it never sent and the ones it
+  /// sent more than once.
+  Report { missing: &'a [String], duplicates: &'a [String] },
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+  timestamp: DateTime<Utc>,
+  #[serde(flatten)]
+  event: &'a Event<'a>,
+}
+
+/// A JSON Lines transcript of a protocol session. Clones write to the same file, so a single
+/// transcript covers every attempt of a retried session.
+#[derive(Clone)]
+pub struct Transcript {
+  writer: Arc<Mutex<BufWriter<File>>>,
+  redact: bool,
+}
+
+impl Transcript {
+  /// Create a transcript file, redacting personal data if `redact` is set.
+  pub fn new(filename: &Path, redact: bool) -> Res<Self> {
+    let writer = BufWriter::new(File::create(filename)?);
+    debug!("Created transcript file `{}` (redacted: {})", filename.display(), redact);
+    Ok(Self { writer: Arc::new(Mutex::new(writer)), redact })
+  }
+
+  /// Get whether personal data is redacted from the transcript.
+  pub fn redact(&self) -> bool {
+    self.redact
+  }
+
+  pub(crate) fn record(&mut self, event: &Event) -> Res<()> {
+    let record = Record { timestamp: Utc::now(), event };
+    let mut writer = self.writer.lock();
+    serde_json::to_writer(&mut *writer, &record)?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+    Ok(())
+  }
+}
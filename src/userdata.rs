@@ -4,6 +4,8 @@ use crate::Res;
 use chrono::NaiveDate;
 use log::debug;
 use serde::{Deserialize, Deserializer};
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::BufReader;
 use std::path::Path;
@@ -19,6 +21,23 @@ pub struct UserData {
   // https://www.countries-ofthe-world.com/all-countries.html
   country: String,
   address: Vec<String>,
+  // Any other fields (e.g. phone), for commands added through the field mapping
+  #[serde(flatten)]
+  extra: HashMap<String, Extra>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Extra {
+  List(Vec<String>),
+  Text(String),
+  Other(serde_json::Value),
+}
+
+/// A user data value looked up by field name.
+pub enum Value<'a> {
+  Text(Cow<'a, str>),
+  List(&'a [String]),
 }
 
 fn deserialize_birthdate<'de, D>(deserializer: D) -> Result<NaiveDate, D::Error>
@@ -53,6 +72,10 @@ impl UserData {
       debug!("    {}", address_line);
     }
 
+    for name in user_data.extra.keys() {
+      debug!("  Extra field: {}", name);
+    }
+
     Ok(user_data)
   }
 
@@ -105,4 +128,25 @@ impl UserData {
   pub fn address_line(&self, n: usize) -> &str {
     &self.address[n]
   }
+
+  /// Look up a field by name, e.g. `emails` or an extra field such as `phone`. Dates are
+  /// formatted as %d.%m.%Y and missing or null fields are `None`.
+  pub fn value(&self, field: &str) -> Option<Value<'_>> {
+    let value = match field {
+      "name" => Value::Text(Cow::from(self.name.as_str())),
+      "emails" => Value::List(&self.emails),
+      "skype" => Value::Text(Cow::from(self.skype.as_deref()?)),
+      "birth_date" => Value::Text(Cow::from(self.birth_date_as_string())),
+      "country" => Value::Text(Cow::from(self.country.as_str())),
+      "address" => Value::List(&self.address),
+      field => match self.extra.get(field)? {
+        Extra::List(list) => Value::List(list),
+        Extra::Text(text) => Value::Text(Cow::from(text.as_str())),
+        Extra::Other(serde_json::Value::Null) => return None,
+        Extra::Other(other) => Value::Text(Cow::from(other.to_string())),
+      },
+    };
+
+    Some(value)
+  }
 }
@@ -0,0 +1,56 @@
+#![warn(clippy::all)]
+
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+const USERDATA: &str = r#"{"name": "Jane Doe", "emails": ["jane@example.com"], "birth_date": "01.02.1990",
+  "country": "Germany", "address": ["Street 1"]}"#;
+
+/// Write a file named after the test to the temporary directory and return its name.
+fn write(test: &str, name: &str, contents: &str) -> PathBuf {
+  let filename = std::env::temp_dir().join(format!("exasol-cli-{}-{}-{}", test, std::process::id(), name));
+  std::fs::write(&filename, contents).unwrap();
+  filename
+}
+
+/// Run the client with the given arguments at the default verbosity.
+fn exasol(args: &[&str]) -> Output {
+  Command::new(env!("CARGO_BIN_EXE_exasol")).args(args).output().unwrap()
+}
+
+#[test]
+fn failed_replay_exits_non_zero() {
+  let data_file = write("replay", "data.json", USERDATA);
+  let transcript_file = write(
+    "replay",
+    "transcript.jsonl",
+    "{\"event\": \"received\", \"line\": \"HELO\"}\n{\"event\": \"sent\", \"line\": \"HELLO\"}\n",
+  );
+
+  let output = exasol(&[
+    "replay",
+    "-d",
+    data_file.to_str().unwrap(),
+    transcript_file.to_str().unwrap(),
+  ]);
+  std::fs::remove_file(data_file).unwrap();
+  std::fs::remove_file(transcript_file).unwrap();
+
+  assert_eq!(output.status.code(), Some(1));
+  assert!(String::from_utf8_lossy(&output.stderr).contains("Error: "));
+}
+
+#[test]
+fn failed_diagnosis_exits_non_zero() {
+  // A port that was just free, so that nothing listens on it.
+  let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+  let server = format!("127.0.0.1:{}", port);
+  let cert_file = concat!(env!("CARGO_MANIFEST_DIR"), "/files/cert-and-key.pem");
+
+  let output = exasol(&["diagnose", "--cert-file", cert_file, "--server", &server]);
+
+  assert_eq!(output.status.code(), Some(1));
+  assert!(String::from_utf8_lossy(&output.stdout).contains("[FAIL] TCP connection"));
+  assert!(String::from_utf8_lossy(&output.stderr).contains("Error: Some checks failed"));
+}